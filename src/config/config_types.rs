@@ -1,6 +1,9 @@
 use std::{
     fmt::{self, Display},
+    net::IpAddr,
+    path::PathBuf,
     str::FromStr,
+    time::Duration,
 };
 
 #[derive(Debug, Clone, PartialEq, Eq, Default)]
@@ -14,6 +17,622 @@ impl FromStr for ProgArgs {
     }
 }
 
+/// Environment variable assignments: shell escaped "KEY=VALUE" words
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct EnvVars(pub Vec<(String, String)>);
+
+impl FromStr for EnvVars {
+    type Err = &'static str;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let words = shlex::split(s).ok_or("Invalid shell escaped string")?;
+        let vars = words
+            .into_iter()
+            .map(|word| match word.split_once('=') {
+                Some((key, value)) if is_env_name(key) => Ok((key.to_string(), value.to_string())),
+                _ => Err("Invalid input: must be a list of KEY=VALUE"),
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self(vars))
+    }
+}
+
+/// Whitespace separated list of environment variable names
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct EnvNames(pub Vec<String>);
+
+impl FromStr for EnvNames {
+    type Err = &'static str;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let names: Vec<String> = s.split_whitespace().map(str::to_string).collect();
+        if !names.iter().all(|name| is_env_name(name)) {
+            return Err("Invalid input: must be a list of variable names");
+        }
+        Ok(Self(names))
+    }
+}
+
+/// Whether `name` can be an environment variable name
+pub fn is_env_name(name: &str) -> bool {
+    !name.is_empty() && !name.contains(|c: char| c == '=' || c == '\0' || c.is_whitespace())
+}
+
+/// Boolean option, written as "yes" or "no"
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct YesNo(pub bool);
+
+impl FromStr for YesNo {
+    type Err = &'static str;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "yes" | "true" => Ok(Self(true)),
+            "no" | "false" => Ok(Self(false)),
+            _ => Err("Invalid input: must be yes|no"),
+        }
+    }
+}
+
+/// User or group, by name or numeric ID
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Account {
+    Id(u32),
+    Name(String),
+}
+
+impl FromStr for Account {
+    type Err = &'static str;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() || s.contains(char::is_whitespace) {
+            return Err("Invalid input: must be a name or numeric ID");
+        }
+        match s.parse() {
+            Ok(id) => Ok(Self::Id(id)),
+            Err(_) if s.bytes().all(|b| b.is_ascii_digit()) => {
+                Err("Invalid input: ID is too large")
+            }
+            Err(_) => Ok(Self::Name(s.to_string())),
+        }
+    }
+}
+
+impl Display for Account {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Id(id) => write!(f, "{}", id),
+            Self::Name(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+/// Numeric limit, or "UNLIMITED"
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Limit(pub Option<u32>);
+
+impl FromStr for Limit {
+    type Err = &'static str;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("unlimited") {
+            return Ok(Self(None));
+        }
+        s.parse()
+            .map(|limit| Self(Some(limit)))
+            .map_err(|_| "Invalid input: must be a number or UNLIMITED")
+    }
+}
+
+/// What to do with connections a service cannot take, i.e. over its `instances` limit or outside
+/// its `access_times`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitAction {
+    /// Stop accepting, leaving connections in the kernel backlog
+    Pause,
+
+    /// Accept and immediately close connections
+    Reject,
+}
+
+impl FromStr for LimitAction {
+    type Err = &'static str;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "pause" => Ok(Self::Pause),
+            "reject" => Ok(Self::Reject),
+            _ => Err("Invalid input: must be pause|reject"),
+        }
+    }
+}
+
+/// Split a number from its unit suffix, e.g. "64M" into ("64", "M")
+fn split_suffix(s: &str) -> (&str, &str) {
+    let unit_start = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    s.split_at(unit_start)
+}
+
+/// Resource size in bytes with an optional K/M/G (binary) suffix, or "UNLIMITED"
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RlimitSize(pub Option<u64>);
+
+impl FromStr for RlimitSize {
+    type Err = &'static str;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        const ERR: &str = "Invalid input: must be a size (e.g. 512K, 64M, 1G) or UNLIMITED";
+        if s.eq_ignore_ascii_case("unlimited") {
+            return Ok(Self(None));
+        }
+        let (number, unit) = split_suffix(s);
+        let multiplier: u64 = match unit.to_ascii_uppercase().as_str() {
+            "" | "B" => 1,
+            "K" => 1 << 10,
+            "M" => 1 << 20,
+            "G" => 1 << 30,
+            _ => return Err(ERR),
+        };
+        let number: u64 = number.parse().map_err(|_| ERR)?;
+        let bytes = number
+            .checked_mul(multiplier)
+            .ok_or("Invalid input: size is too large")?;
+        Ok(Self(Some(bytes)))
+    }
+}
+
+/// Resource time in seconds with an optional s/m/h suffix, or "UNLIMITED"
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RlimitTime(pub Option<u64>);
+
+impl FromStr for RlimitTime {
+    type Err = &'static str;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        const ERR: &str = "Invalid input: must be a time (e.g. 30s, 5m, 1h) or UNLIMITED";
+        if s.eq_ignore_ascii_case("unlimited") {
+            return Ok(Self(None));
+        }
+        let (number, unit) = split_suffix(s);
+        let multiplier: u64 = match unit.to_ascii_lowercase().as_str() {
+            "" | "s" => 1,
+            "m" => 60,
+            "h" => 60 * 60,
+            _ => return Err(ERR),
+        };
+        let number: u64 = number.parse().map_err(|_| ERR)?;
+        let secs = number
+            .checked_mul(multiplier)
+            .ok_or("Invalid input: time is too large")?;
+        Ok(Self(Some(secs)))
+    }
+}
+
+/// File mode creation mask, in octal (e.g. "022")
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Umask(pub u32);
+
+impl FromStr for Umask {
+    type Err = &'static str;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match u32::from_str_radix(s, 8) {
+            Ok(umask) if umask <= 0o777 => Ok(Self(umask)),
+            _ => Err("Invalid input: must be an octal mode from 000 to 777"),
+        }
+    }
+}
+
+/// Scheduling priority, from -20 (highest) to 19 (lowest)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Nice(pub i32);
+
+impl FromStr for Nice {
+    type Err = &'static str;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.parse() {
+            Ok(nice) if (-20..=19).contains(&nice) => Ok(Self(nice)),
+            _ => Err("Invalid input: must be a number from -20 to 19"),
+        }
+    }
+}
+
+/// I/O scheduling class, see ioprio_set(2)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoprioClass {
+    RealTime,
+    BestEffort,
+    Idle,
+}
+
+impl FromStr for IoprioClass {
+    type Err = &'static str;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "realtime" | "rt" => Ok(Self::RealTime),
+            "best-effort" | "be" => Ok(Self::BestEffort),
+            "idle" => Ok(Self::Idle),
+            _ => Err("Invalid input: must be realtime|best-effort|idle"),
+        }
+    }
+}
+
+/// I/O priority within the class, from 0 (highest) to 7 (lowest)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ioprio(pub u8);
+
+impl FromStr for Ioprio {
+    type Err = &'static str;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.parse() {
+            Ok(level) if level <= 7 => Ok(Self(level)),
+            _ => Err("Invalid input: must be a number from 0 to 7"),
+        }
+    }
+}
+
+/// CPU list, e.g. "0-3,6"
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CpuList(pub Vec<usize>);
+
+impl CpuList {
+    /// Number of CPUs a CPU set can hold
+    pub const MAX_CPUS: usize = libc::CPU_SETSIZE as usize;
+}
+
+impl FromStr for CpuList {
+    type Err = &'static str;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        const ERR: &str = "Invalid input: must be a list of CPUs or ranges, e.g. 0-3,6";
+        let mut cpus = Vec::new();
+        for item in s.split(',').map(str::trim) {
+            let (first, last) = match item.split_once('-') {
+                Some((first, last)) => (first, last),
+                None => (item, item),
+            };
+            let first: usize = first.trim().parse().map_err(|_| ERR)?;
+            let last: usize = last.trim().parse().map_err(|_| ERR)?;
+            if first > last {
+                return Err(ERR);
+            }
+            if last >= Self::MAX_CPUS {
+                return Err("Invalid input: CPU number is too large");
+            }
+            cpus.extend(first..=last);
+        }
+        cpus.sort_unstable();
+        cpus.dedup();
+        Ok(Self(cpus))
+    }
+}
+
+/// OOM killer score adjustment, from -1000 (never kill) to 1000 (kill first)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OomScoreAdj(pub i16);
+
+impl FromStr for OomScoreAdj {
+    type Err = &'static str;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.parse() {
+            Ok(adj) if (-1000..=1000).contains(&adj) => Ok(Self(adj)),
+            _ => Err("Invalid input: must be a number from -1000 to 1000"),
+        }
+    }
+}
+
+/// Kind of a service that does not simply spawn its `server`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ServiceType {
+    /// Served by yinetd itself, see [InternalService]
+    Internal,
+
+    /// Selected by name through the `tcpmux` internal service (RFC 1078); the server sends the
+    /// "+" reply itself
+    Tcpmux,
+
+    /// Like [Tcpmux](Self::Tcpmux), but yinetd sends the "+" reply before starting the server
+    TcpmuxPlus,
+}
+
+impl FromStr for ServiceType {
+    type Err = &'static str;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_uppercase().as_str() {
+            "INTERNAL" => Ok(Self::Internal),
+            "TCPMUX" => Ok(Self::Tcpmux),
+            "TCPMUXPLUS" => Ok(Self::TcpmuxPlus),
+            _ => Err("Invalid input: must be INTERNAL|TCPMUX|TCPMUXPLUS"),
+        }
+    }
+}
+
+/// Service built into yinetd
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InternalService {
+    /// RFC 862: send back what the client sends
+    Echo,
+
+    /// RFC 863: ignore what the client sends
+    Discard,
+
+    /// RFC 867: send the local time as text
+    Daytime,
+
+    /// RFC 864: send lines of characters until the client closes
+    Chargen,
+
+    /// RFC 868: send the time as seconds since 1900
+    Time,
+
+    /// RFC 1078: hand connections to TCPMUX services by name
+    Tcpmux,
+}
+
+impl FromStr for InternalService {
+    type Err = &'static str;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "echo" => Ok(Self::Echo),
+            "discard" => Ok(Self::Discard),
+            "daytime" => Ok(Self::Daytime),
+            "chargen" => Ok(Self::Chargen),
+            "time" => Ok(Self::Time),
+            "tcpmux" => Ok(Self::Tcpmux),
+            _ => Err("Invalid input: must be echo|discard|daytime|chargen|time|tcpmux"),
+        }
+    }
+}
+
+/// Where a server's stderr goes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StderrMode {
+    /// To the client, like stdout
+    Socket,
+
+    /// Appended to a file
+    File(PathBuf),
+
+    /// Discarded
+    Null,
+
+    /// Logged by yinetd at info level, line by line
+    Log,
+}
+
+impl FromStr for StderrMode {
+    type Err = &'static str;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        const ERR: &str = "Invalid input: must be socket|file:<path>|null|log";
+        if let Some(path) = s.strip_prefix("file:") {
+            if path.is_empty() {
+                return Err(ERR);
+            }
+            return Ok(Self::File(PathBuf::from(path)));
+        }
+        match s.to_lowercase().as_str() {
+            "socket" => Ok(Self::Socket),
+            "null" => Ok(Self::Null),
+            "log" => Ok(Self::Log),
+            _ => Err(ERR),
+        }
+    }
+}
+
+/// TCP address connections are forwarded to: "<host> <port>"
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Redirect {
+    /// Host name or IP address
+    pub host: String,
+    pub port: u16,
+}
+
+impl FromStr for Redirect {
+    type Err = &'static str;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        const ERR: &str = "Invalid input: must be <host> <port>";
+        let (host, port) = match s.split_whitespace().collect::<Vec<_>>().as_slice() {
+            [host, port] => (host.to_string(), port.parse().map_err(|_| ERR)?),
+            _ => return Err(ERR),
+        };
+        if port == 0 {
+            return Err("Invalid input: port must be positive");
+        }
+        Ok(Self { host, port })
+    }
+}
+
+impl Display for Redirect {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.host.contains(':') {
+            true => write!(f, "[{}]:{}", self.host, self.port),
+            false => write!(f, "{}:{}", self.host, self.port),
+        }
+    }
+}
+
+/// PROXY protocol header that a load balancer sends at the start of connections
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProxyProtocol {
+    /// Text header
+    V1,
+
+    /// Binary header
+    V2,
+
+    /// Either header, or none
+    Optional,
+}
+
+impl FromStr for ProxyProtocol {
+    type Err = &'static str;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "v1" => Ok(Self::V1),
+            "v2" => Ok(Self::V2),
+            "optional" => Ok(Self::Optional),
+            _ => Err("Invalid input: must be v1|v2|optional"),
+        }
+    }
+}
+
+/// Connection rate limit: "<conns_per_sec> <disable_secs> [burst]"
+///
+/// Once a service accepts connections faster than `rate` (allowing bursts of `burst`
+/// connections), it stops accepting for `disable`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cps {
+    pub rate: u32,
+    pub disable: Duration,
+    pub burst: u32,
+}
+
+impl FromStr for Cps {
+    type Err = &'static str;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        const ERR: &str = "Invalid input: must be <conns_per_sec> <disable_secs> [burst]";
+        let fields: Vec<&str> = s.split_whitespace().collect();
+        let (rate, disable_secs, burst) = match fields.as_slice() {
+            [rate, disable_secs] => (rate, disable_secs, None),
+            [rate, disable_secs, burst] => (rate, disable_secs, Some(burst)),
+            _ => return Err(ERR),
+        };
+        let rate: u32 = rate.parse().map_err(|_| ERR)?;
+        let disable_secs: u64 = disable_secs.parse().map_err(|_| ERR)?;
+        let burst: u32 = match burst {
+            Some(burst) => burst.parse().map_err(|_| ERR)?,
+            None => rate,
+        };
+        if rate == 0 || burst == 0 {
+            return Err("Invalid input: connection rate and burst must be positive");
+        }
+        Ok(Self {
+            rate,
+            disable: Duration::from_secs(disable_secs),
+            burst,
+        })
+    }
+}
+
+/// Local time of day interval "HH:MM-HH:MM", including the end minute.
+///
+/// An interval whose end is before its start spans midnight.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeWindow {
+    /// Minutes since midnight
+    pub start: u16,
+    /// Minutes since midnight
+    pub end: u16,
+}
+
+impl TimeWindow {
+    pub const MINUTES_PER_DAY: u16 = 24 * 60;
+
+    /// Whether the window includes `minute` (since midnight)
+    pub fn contains(&self, minute: u16) -> bool {
+        if self.start <= self.end {
+            self.start <= minute && minute <= self.end
+        } else {
+            minute >= self.start || minute <= self.end
+        }
+    }
+}
+
+impl FromStr for TimeWindow {
+    type Err = &'static str;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        const ERR: &str = "Invalid input: must be HH:MM-HH:MM";
+        fn minutes(time: &str) -> Option<u16> {
+            let (hours, minutes) = time.split_once(':')?;
+            let hours: u16 = hours.parse().ok()?;
+            let minutes: u16 = minutes.parse().ok()?;
+            if hours >= 24 || minutes >= 60 {
+                return None;
+            }
+            Some(hours * 60 + minutes)
+        }
+        let (start, end) = s.split_once('-').ok_or(ERR)?;
+        Ok(Self {
+            start: minutes(start).ok_or(ERR)?,
+            end: minutes(end).ok_or(ERR)?,
+        })
+    }
+}
+
+/// Whitespace separated list of [TimeWindow]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AccessTimes(pub Vec<TimeWindow>);
+
+impl FromStr for AccessTimes {
+    type Err = &'static str;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let windows = s
+            .split_whitespace()
+            .map(str::parse)
+            .collect::<Result<Vec<_>, _>>()?;
+        if windows.is_empty() {
+            return Err("Invalid input: must list at least one HH:MM-HH:MM interval");
+        }
+        Ok(Self(windows))
+    }
+}
+
+/// Address pattern used for access control
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AddrPattern {
+    /// Network in CIDR notation; a plain address has the full prefix length
+    Net { addr: IpAddr, prefix_len: u8 },
+
+    /// Host name, resolved through `/etc/hosts`
+    Host(String),
+}
+
+impl FromStr for AddrPattern {
+    type Err = &'static str;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some((addr, prefix_len)) = s.split_once('/') {
+            let addr: IpAddr = addr
+                .parse()
+                .map_err(|_| "Invalid input: invalid network address")?;
+            let prefix_len: u8 = prefix_len
+                .parse()
+                .map_err(|_| "Invalid input: invalid network prefix length")?;
+            if prefix_len > max_prefix_len(addr) {
+                return Err("Invalid input: network prefix length is too long");
+            }
+            return Ok(Self::Net { addr, prefix_len });
+        }
+        if let Ok(addr) = s.parse::<IpAddr>() {
+            // Like xinetd, the unspecified IPv4 address matches everything
+            let prefix_len = if addr == IpAddr::from([0, 0, 0, 0]) {
+                0
+            } else {
+                max_prefix_len(addr)
+            };
+            return Ok(Self::Net { addr, prefix_len });
+        }
+        let valid_host = !s.is_empty()
+            && s.chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-' || c == '_');
+        if !valid_host {
+            return Err("Invalid input: must be an address, network or host name");
+        }
+        Ok(Self::Host(s.to_string()))
+    }
+}
+
+pub(crate) fn max_prefix_len(addr: IpAddr) -> u8 {
+    match addr {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    }
+}
+
+/// Whitespace separated list of [AddrPattern]
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct AddrList(pub Vec<AddrPattern>);
+
+impl FromStr for AddrList {
+    type Err = &'static str;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let patterns = s
+            .split_whitespace()
+            .map(|pattern| pattern.parse())
+            .collect::<Result<_, _>>()?;
+        Ok(Self(patterns))
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum SocketType {
     /// "stream"
@@ -21,6 +640,19 @@ pub enum SocketType {
 
     /// "dgram"
     Udp,
+
+    /// "unix" (Unix domain stream socket)
+    Unix,
+
+    /// "unix_dgram" (Unix domain datagram socket)
+    UnixDgram,
+}
+
+impl SocketType {
+    /// Whether services of this type are handed the bound socket itself
+    pub fn is_datagram(self) -> bool {
+        matches!(self, Self::Udp | Self::UnixDgram)
+    }
 }
 
 impl FromStr for SocketType {
@@ -29,7 +661,40 @@ impl FromStr for SocketType {
         match s.to_lowercase().as_str() {
             "tcp" | "stream" => Ok(Self::Tcp),
             "udp" | "dgram" => Ok(Self::Udp),
-            _ => Err("Invalid input: must be tcp|udp (or alises stream|dgram)"),
+            "unix" => Ok(Self::Unix),
+            "unix_dgram" => Ok(Self::UnixDgram),
+            _ => Err("Invalid input: must be tcp|udp|unix|unix_dgram (or aliases stream|dgram)"),
+        }
+    }
+}
+
+/// Unix domain socket address
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UnixAddr {
+    /// Filesystem path
+    Path(PathBuf),
+
+    /// Linux abstract namespace name, written as "@name"
+    Abstract(String),
+}
+
+impl FromStr for UnixAddr {
+    type Err = &'static str;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix('@') {
+            Some("") => Err("Invalid input: abstract socket name must not be empty"),
+            Some(name) => Ok(Self::Abstract(name.to_string())),
+            None if s.is_empty() => Err("Invalid input: socket path must not be empty"),
+            None => Ok(Self::Path(s.into())),
+        }
+    }
+}
+
+impl Display for UnixAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Path(path) => write!(f, "{}", path.display()),
+            Self::Abstract(name) => write!(f, "@{}", name),
         }
     }
 }
@@ -71,9 +736,266 @@ mod test {
     fn socket_type() {
         assert_eq!("tcp".parse::<SocketType>(), Ok(SocketType::Tcp));
         assert_eq!("UDP".parse::<SocketType>(), Ok(SocketType::Udp));
+        assert_eq!("unix".parse::<SocketType>(), Ok(SocketType::Unix));
+        assert_eq!(
+            "unix_dgram".parse::<SocketType>(),
+            Ok(SocketType::UnixDgram)
+        );
         assert!("blah".parse::<SocketType>().is_err());
     }
 
+    #[test]
+    fn yes_no() {
+        assert_eq!("yes".parse::<YesNo>(), Ok(YesNo(true)));
+        assert_eq!("No".parse::<YesNo>(), Ok(YesNo(false)));
+        assert_eq!("true".parse::<YesNo>(), Ok(YesNo(true)));
+        assert!("maybe".parse::<YesNo>().is_err());
+    }
+
+    #[test]
+    fn env() {
+        assert_eq!(
+            r#"A=1 B="two words" C="#.parse::<EnvVars>(),
+            Ok(EnvVars(vec![
+                ("A".to_string(), "1".to_string()),
+                ("B".to_string(), "two words".to_string()),
+                ("C".to_string(), "".to_string()),
+            ]))
+        );
+        assert!("A".parse::<EnvVars>().is_err());
+        assert!("=1".parse::<EnvVars>().is_err());
+        assert_eq!(
+            "HOME  LANG".parse::<EnvNames>(),
+            Ok(EnvNames(vec!["HOME".to_string(), "LANG".to_string()]))
+        );
+        assert!("A=1".parse::<EnvNames>().is_err());
+    }
+
+    #[test]
+    fn account() {
+        assert_eq!("0".parse::<Account>(), Ok(Account::Id(0)));
+        assert_eq!(
+            "nobody".parse::<Account>(),
+            Ok(Account::Name("nobody".to_string()))
+        );
+        assert_eq!(
+            "www-data".parse::<Account>(),
+            Ok(Account::Name("www-data".to_string()))
+        );
+        assert!("99999999999".parse::<Account>().is_err());
+        assert!("".parse::<Account>().is_err());
+        assert!("a b".parse::<Account>().is_err());
+    }
+
+    #[test]
+    fn limit() {
+        assert_eq!("5".parse::<Limit>(), Ok(Limit(Some(5))));
+        assert_eq!("UNLIMITED".parse::<Limit>(), Ok(Limit(None)));
+        assert!("-1".parse::<Limit>().is_err());
+        assert_eq!("Reject".parse::<LimitAction>(), Ok(LimitAction::Reject));
+        assert!("drop".parse::<LimitAction>().is_err());
+    }
+
+    #[test]
+    fn cps() {
+        assert_eq!(
+            "10 30".parse::<Cps>(),
+            Ok(Cps {
+                rate: 10,
+                disable: Duration::from_secs(30),
+                burst: 10,
+            })
+        );
+        assert_eq!(
+            "10\t30 50".parse::<Cps>(),
+            Ok(Cps {
+                rate: 10,
+                disable: Duration::from_secs(30),
+                burst: 50,
+            })
+        );
+        assert!("10".parse::<Cps>().is_err());
+        assert!("0 30".parse::<Cps>().is_err());
+        assert!("10 30 50 70".parse::<Cps>().is_err());
+    }
+
+    #[test]
+    fn rlimits() {
+        assert_eq!("4096".parse::<RlimitSize>(), Ok(RlimitSize(Some(4096))));
+        assert_eq!(
+            "512K".parse::<RlimitSize>(),
+            Ok(RlimitSize(Some(512 << 10)))
+        );
+        assert_eq!("64m".parse::<RlimitSize>(), Ok(RlimitSize(Some(64 << 20))));
+        assert_eq!("2G".parse::<RlimitSize>(), Ok(RlimitSize(Some(2 << 30))));
+        assert_eq!("UNLIMITED".parse::<RlimitSize>(), Ok(RlimitSize(None)));
+        assert!("M".parse::<RlimitSize>().is_err());
+        assert!("64T".parse::<RlimitSize>().is_err());
+        assert!("-1".parse::<RlimitSize>().is_err());
+        assert!("99999999999999999999G".parse::<RlimitSize>().is_err());
+
+        assert_eq!("30".parse::<RlimitTime>(), Ok(RlimitTime(Some(30))));
+        assert_eq!("30s".parse::<RlimitTime>(), Ok(RlimitTime(Some(30))));
+        assert_eq!("5m".parse::<RlimitTime>(), Ok(RlimitTime(Some(300))));
+        assert_eq!("1h".parse::<RlimitTime>(), Ok(RlimitTime(Some(3600))));
+        assert_eq!("unlimited".parse::<RlimitTime>(), Ok(RlimitTime(None)));
+        assert!("1d".parse::<RlimitTime>().is_err());
+    }
+
+    #[test]
+    fn umask() {
+        assert_eq!("022".parse::<Umask>(), Ok(Umask(0o022)));
+        assert_eq!("0027".parse::<Umask>(), Ok(Umask(0o027)));
+        assert!("1000".parse::<Umask>().is_err());
+        assert!("089".parse::<Umask>().is_err());
+    }
+
+    #[test]
+    fn scheduling() {
+        assert_eq!("-5".parse::<Nice>(), Ok(Nice(-5)));
+        assert!("20".parse::<Nice>().is_err());
+        assert_eq!("idle".parse::<IoprioClass>(), Ok(IoprioClass::Idle));
+        assert_eq!("BE".parse::<IoprioClass>(), Ok(IoprioClass::BestEffort));
+        assert!("low".parse::<IoprioClass>().is_err());
+        assert_eq!("7".parse::<Ioprio>(), Ok(Ioprio(7)));
+        assert!("8".parse::<Ioprio>().is_err());
+        assert_eq!("-1000".parse::<OomScoreAdj>(), Ok(OomScoreAdj(-1000)));
+        assert!("1001".parse::<OomScoreAdj>().is_err());
+
+        assert_eq!("0-3,6".parse::<CpuList>(), Ok(CpuList(vec![0, 1, 2, 3, 6])));
+        assert_eq!("2, 1-2".parse::<CpuList>(), Ok(CpuList(vec![1, 2])));
+        assert!("3-1".parse::<CpuList>().is_err());
+        assert!("".parse::<CpuList>().is_err());
+        assert!("0-4096".parse::<CpuList>().is_err());
+    }
+
+    #[test]
+    fn internal() {
+        assert_eq!("internal".parse::<ServiceType>(), Ok(ServiceType::Internal));
+        assert_eq!(
+            "TCPMUXPLUS".parse::<ServiceType>(),
+            Ok(ServiceType::TcpmuxPlus)
+        );
+        assert!("external".parse::<ServiceType>().is_err());
+        assert_eq!("echo".parse::<InternalService>(), Ok(InternalService::Echo));
+        assert_eq!(
+            "CHARGEN".parse::<InternalService>(),
+            Ok(InternalService::Chargen)
+        );
+        assert!("qotd".parse::<InternalService>().is_err());
+    }
+
+    #[test]
+    fn stderr_mode() {
+        assert_eq!("socket".parse::<StderrMode>(), Ok(StderrMode::Socket));
+        assert_eq!("NULL".parse::<StderrMode>(), Ok(StderrMode::Null));
+        assert_eq!("log".parse::<StderrMode>(), Ok(StderrMode::Log));
+        assert_eq!(
+            "file:/var/log/echo.err".parse::<StderrMode>(),
+            Ok(StderrMode::File(PathBuf::from("/var/log/echo.err")))
+        );
+        assert!("file:".parse::<StderrMode>().is_err());
+        assert!("syslog".parse::<StderrMode>().is_err());
+    }
+
+    #[test]
+    fn redirect() {
+        let redirect = "db.internal  5432".parse::<Redirect>().unwrap();
+        assert_eq!(
+            redirect,
+            Redirect {
+                host: "db.internal".to_string(),
+                port: 5432,
+            }
+        );
+        assert_eq!(redirect.to_string(), "db.internal:5432");
+        assert_eq!(
+            "2001:db8::1 22".parse::<Redirect>().unwrap().to_string(),
+            "[2001:db8::1]:22"
+        );
+        assert!("db.internal".parse::<Redirect>().is_err());
+        assert!("db.internal 0".parse::<Redirect>().is_err());
+        assert!("db.internal 5432 tcp".parse::<Redirect>().is_err());
+    }
+
+    #[test]
+    fn proxy_protocol() {
+        assert_eq!("V1".parse::<ProxyProtocol>(), Ok(ProxyProtocol::V1));
+        assert_eq!(
+            "optional".parse::<ProxyProtocol>(),
+            Ok(ProxyProtocol::Optional)
+        );
+        assert!("v3".parse::<ProxyProtocol>().is_err());
+    }
+
+    #[test]
+    fn access_times() {
+        assert_eq!(
+            "08:00-18:00 22:30-1:15".parse::<AccessTimes>(),
+            Ok(AccessTimes(vec![
+                TimeWindow {
+                    start: 8 * 60,
+                    end: 18 * 60,
+                },
+                TimeWindow {
+                    start: 22 * 60 + 30,
+                    end: 75,
+                },
+            ]))
+        );
+        assert!("".parse::<AccessTimes>().is_err());
+        assert!("08:00".parse::<AccessTimes>().is_err());
+        assert!("08:00-24:00".parse::<AccessTimes>().is_err());
+        assert!("08:60-09:00".parse::<AccessTimes>().is_err());
+
+        let night = TimeWindow {
+            start: 22 * 60,
+            end: 6 * 60,
+        };
+        assert!(night.contains(23 * 60));
+        assert!(night.contains(0));
+        assert!(night.contains(6 * 60));
+        assert!(!night.contains(6 * 60 + 1));
+        assert!(!night.contains(12 * 60));
+    }
+
+    #[test]
+    fn addr_list() {
+        assert_eq!(
+            "10.0.0.0/8 192.168.1.1 ::1 fe80::/10 localhost".parse::<AddrList>(),
+            Ok(AddrList(vec![
+                AddrPattern::Net {
+                    addr: "10.0.0.0".parse().unwrap(),
+                    prefix_len: 8
+                },
+                AddrPattern::Net {
+                    addr: "192.168.1.1".parse().unwrap(),
+                    prefix_len: 32
+                },
+                AddrPattern::Net {
+                    addr: "::1".parse().unwrap(),
+                    prefix_len: 128
+                },
+                AddrPattern::Net {
+                    addr: "fe80::".parse().unwrap(),
+                    prefix_len: 10
+                },
+                AddrPattern::Host("localhost".to_string()),
+            ]))
+        );
+        assert_eq!(
+            "0.0.0.0".parse::<AddrPattern>(),
+            Ok(AddrPattern::Net {
+                addr: "0.0.0.0".parse().unwrap(),
+                prefix_len: 0
+            })
+        );
+        assert_eq!("".parse::<AddrList>(), Ok(AddrList(vec![])));
+        assert!("10.0.0.0/33".parse::<AddrList>().is_err());
+        assert!("10.0.0.0/x".parse::<AddrList>().is_err());
+        assert!("bad!host".parse::<AddrList>().is_err());
+    }
+
     #[test]
     fn prog_args() {
         assert_eq!("".parse::<ProgArgs>(), Ok(ProgArgs(vec![])));
@@ -99,6 +1021,20 @@ mod test {
         );
     }
 
+    #[test]
+    fn unix_addr() {
+        assert_eq!(
+            "/run/foo.sock".parse::<UnixAddr>(),
+            Ok(UnixAddr::Path("/run/foo.sock".into()))
+        );
+        assert_eq!(
+            "@foo".parse::<UnixAddr>(),
+            Ok(UnixAddr::Abstract("foo".to_string()))
+        );
+        assert!("@".parse::<UnixAddr>().is_err());
+        assert!("".parse::<UnixAddr>().is_err());
+    }
+
     #[test]
     fn inet_type() {
         assert_eq!("ipv4".parse::<InetType>(), Ok(InetType::Ipv4));
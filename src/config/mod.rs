@@ -38,4 +38,8 @@ impl Config {
     pub fn services(&self) -> &[Service] {
         &self.services
     }
+
+    pub fn into_services(self) -> Vec<Service> {
+        self.services
+    }
 }
@@ -24,7 +24,7 @@ use crate::{
 #[grammar = "config/config_grammar.pest"]
 struct ConfigParser;
 
-fn parse_config_str(config: &str) -> Result<Config> {
+pub(crate) fn parse_config_str(config: &str) -> Result<Config> {
     let mut parser = ConfigParser::parse(Rule::file, config)?;
 
     let mut config = Config::new();
@@ -57,6 +57,7 @@ fn parse_config_str(config: &str) -> Result<Config> {
                 service_option.fill_with_defaults(&default_options);
 
                 let service = Service::from_optioned(service_option, service_name, &pair)?;
+                service.check(&pair)?;
                 config.add_service(service)?;
             }
             Rule::EOI => {}
@@ -70,6 +71,16 @@ fn parse_config_str(config: &str) -> Result<Config> {
     Ok(config)
 }
 
+/// Parse the service "s", with `options` on top of the required "server" and "port" options
+#[cfg(test)]
+pub(crate) fn parse_test_service(options: &str) -> Service {
+    let config = format!(
+        "service s\n{{\n    server = s\n    port = 1\n    {}\n}}\n",
+        options
+    );
+    parse_config_str(&config).unwrap().into_services().remove(0)
+}
+
 pub fn parse_config_file<P: AsRef<Path>>(path: P) -> Result<Config> {
     let path: &Path = path.as_ref();
     let file = File::open(path).map_err(|err| Error::Config {
@@ -1,9 +1,16 @@
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    path::PathBuf,
+};
 
 use once_cell::sync::Lazy;
 
 use crate::{
-    config::config_types::{InetType, SocketType},
+    config::config_types::{
+        AccessTimes, Account, AddrList, AddrPattern, CpuList, InetType, InternalService,
+        IoprioClass, Limit, LimitAction, Nice, OomScoreAdj, ProxyProtocol, Redirect, RlimitSize,
+        RlimitTime, ServiceType, SocketType, StderrMode, TimeWindow, Umask, UnixAddr, YesNo,
+    },
     Error,
 };
 
@@ -242,6 +249,64 @@ service service_a
 }
 "#;
 
+const FAIL_BAD_OPTION_RLIMIT: &str = r#"
+service service_a
+{
+    port = 1234
+    server = server
+    rlimit_as = 64T
+}
+"#;
+
+const PASS_RLIMITS: &str = r#"
+default {
+    rlimit_core = 0
+}
+
+service service_a
+{
+    port = 1234
+    server = server
+    rlimit_as = 64M
+    rlimit_cpu = 30s
+    rlimit_files = UNLIMITED
+}
+"#;
+
+const PASS_PROCESS_ATTRIBUTES: &str = r#"
+service service_a
+{
+    port = 1234
+    server = server
+    nice = 10
+    ioprio_class = idle
+    cpu_affinity = 0-1,3
+    oom_score_adj = 500
+    umask = 027
+    working_directory = /home/ftp
+    chroot = /srv/jail
+}
+"#;
+
+const PASS_STDERR: &str = r#"
+default {
+    stderr = log
+}
+
+service service_a
+{
+    port = 1234
+    server = server
+}
+
+service service_b
+{
+    port = 1235
+    server = server
+    stderr = file:/var/log/service-b.err
+}
+"#;
+
 const FAIL_MISMACH_EXPECT_IPV4: &str = r#"
 service service_a
 {
@@ -262,15 +327,336 @@ service service_a
 }
 "#;
 
+const PASS_UNIX: &str = r#"
+service service_a
+{
+    server = server
+    socket_type = unix
+    listen_path = /run/service-a.sock
+}
+
+service service_b
+{
+    server = server
+    listen_path = /run/service-b.sock
+}
+"#;
+
+const FAIL_UNIX_MISSING_PATH: &str = r#"
+service service_a
+{
+    server = server
+    socket_type = unix
+}
+"#;
+
+const FAIL_MISSING_PORT: &str = r#"
+service service_a
+{
+    server = server
+    socket_type = udp
+}
+"#;
+
+const FAIL_UNIX_WITH_PORT: &str = r#"
+service service_a
+{
+    server = server
+    port = 1234
+    listen_path = /run/service-a.sock
+}
+"#;
+
+const PASS_UNIX_DGRAM: &str = r#"
+service service_a
+{
+    server = server
+    socket_type = unix_dgram
+    listen_path = @service-a
+}
+
+service service_b
+{
+    server = server
+    socket_type = dgram
+    listen_path = /run/service-b.sock
+}
+"#;
+
+const PASS_WAIT: &str = r#"
+service service_a
+{
+    server = server
+    port = 1234
+    wait = yes
+}
+
+service service_b
+{
+    server = server
+    port = 1234
+    wait = no
+}
+
+service service_c
+{
+    server = server
+    port = 1234
+    socket_type = udp
+}
+"#;
+
+const FAIL_DGRAM_NO_WAIT: &str = r#"
+service service_a
+{
+    server = server
+    port = 1234
+    socket_type = udp
+    wait = no
+}
+"#;
+
+const PASS_INTERNAL: &str = r#"
+service echo
+{
+    type = INTERNAL
+    port = 7
+}
+
+service chargen_udp
+{
+    type = internal
+    server = chargen
+    socket_type = udp
+    port = 19
+}
+"#;
+
+const FAIL_INTERNAL_UNKNOWN: &str = r#"
+service qotd
+{
+    type = INTERNAL
+    port = 17
+}
+"#;
+
+const FAIL_INTERNAL_WAIT: &str = r#"
+service echo
+{
+    type = INTERNAL
+    port = 7
+    wait = yes
+}
+"#;
+
+const PASS_TCPMUX: &str = r#"
+service tcpmux
+{
+    type = INTERNAL
+    port = 1
+}
+
+service backup
+{
+    type = TCPMUXPLUS
+    server = /usr/sbin/backupd
+}
+"#;
+
+const FAIL_TCPMUX_UDP: &str = r#"
+service backup
+{
+    type = TCPMUX
+    server = /usr/sbin/backupd
+    socket_type = udp
+}
+"#;
+
+const FAIL_TCPMUX_NO_SERVER: &str = r#"
+service backup
+{
+    type = TCPMUX
+}
+"#;
+
+const PASS_REDIRECT: &str = r#"
+service postgres
+{
+    redirect = db.internal 5432
+    port = 5432
+    only_from = 192.0.2.0/24
+}
+"#;
+
+const FAIL_REDIRECT_SERVER: &str = r#"
+service postgres
+{
+    redirect = db.internal 5432
+    server = /usr/sbin/postgres
+    port = 5432
+}
+"#;
+
+const FAIL_REDIRECT_UDP: &str = r#"
+service dns
+{
+    redirect = 192.0.2.53 53
+    socket_type = udp
+    port = 53
+}
+"#;
+
+const PASS_PROXY_PROTOCOL: &str = r#"
+service ssh
+{
+    server = /usr/sbin/sshd
+    server_args = -i
+    port = 22
+    proxy_protocol = v2
+    only_from = 192.0.2.0/24
+}
+"#;
+
+const FAIL_PROXY_PROTOCOL_WAIT: &str = r#"
+service ssh
+{
+    server = /usr/sbin/sshd
+    port = 22
+    wait = yes
+    proxy_protocol = v1
+}
+"#;
+
+const PASS_USER: &str = r#"
+default {
+    group = nogroup
+}
+
+service service_a
+{
+    server = server
+    port = 1234
+    user = nobody
+}
+
+service service_b
+{
+    server = server
+    port = 1234
+    uid = 1000
+    group = 100
+}
+"#;
+
+const FAIL_USER_AND_UID: &str = r#"
+service service_a
+{
+    server = server
+    port = 1234
+    user = nobody
+    uid = 1000
+}
+"#;
+
+const PASS_INSTANCES: &str = r#"
+default {
+    instances = 10
+}
+
+service service_a
+{
+    server = server
+    port = 1234
+}
+
+service service_b
+{
+    server = server
+    port = 1234
+    instances = UNLIMITED
+}
+
+service service_c
+{
+    server = server
+    port = 1234
+    instances = 2
+    instances_action = reject
+    per_source = 1
+}
+"#;
+
+const PASS_ACCESS: &str = r#"
+default {
+    no_access = 192.0.2.0/24
+}
+
+service service_a
+{
+    server = server
+    port = 1234
+    only_from = 10.0.0.0/8 ::1 localhost
+    access_times = 08:00-12:00 13:00-18:00
+    access_times_action = pause
+}
+
+service service_b
+{
+    server = server
+    port = 1234
+    no_access = 10.1.2.3
+    tcp_wrappers = yes
+    hosts_allow = /tmp/hosts.allow
+}
+"#;
+
 static DEFAULT_SERVICE: Lazy<Service> = Lazy::new(|| Service {
     name: "".to_string(),
-    server: "".to_string(),
-    port: 0,
+    server: None,
+    r#type: None,
+    redirect: None,
+    port: None,
     uid: None,
+    user: None,
+    group: None,
     server_args: Default::default(),
     inet_type: InetType::Ipv4,
     socket_type: SocketType::Tcp,
+    instances: Limit(None),
+    instances_action: LimitAction::Pause,
+    per_source: Limit(None),
+    access_times_action: LimitAction::Reject,
     listen_address: None,
+    wait: None,
+    cps: None,
+    proxy_protocol: None,
+    only_from: None,
+    no_access: None,
+    access_times: None,
+    tcp_wrappers: None,
+    hosts_allow: None,
+    hosts_deny: None,
+    rlimit_as: None,
+    rlimit_cpu: None,
+    rlimit_data: None,
+    rlimit_rss: None,
+    rlimit_stack: None,
+    rlimit_files: None,
+    rlimit_core: None,
+    nice: None,
+    ioprio_class: None,
+    ioprio: None,
+    cpu_affinity: None,
+    oom_score_adj: None,
+    umask: None,
+    working_directory: None,
+    chroot: None,
+    env: None,
+    passenv: None,
+    env_file: None,
+    connection_env: None,
+    stderr: None,
+    listen_path: None,
 });
 
 #[test]
@@ -279,8 +665,8 @@ fn config_no_default() {
         parse_config_str(PASS_NO_DEFAULT).unwrap().services(),
         &[Service {
             name: "service_a".to_string(),
-            server: "/usr/sbin/service-a".to_string(),
-            port: 1234,
+            server: Some("/usr/sbin/service-a".to_string()),
+            port: Some(1234),
             ..DEFAULT_SERVICE.clone()
         }]
     );
@@ -290,8 +676,8 @@ fn config_no_default() {
 fn config_default() {
     let services = &[Service {
         name: "service_a".to_string(),
-        server: "/usr/sbin/service-a".to_string(),
-        port: 1234,
+        server: Some("/usr/sbin/service-a".to_string()),
+        port: Some(1234),
         uid: Some(42),
         ..DEFAULT_SERVICE.clone()
     }];
@@ -313,8 +699,8 @@ fn config_default() {
 fn config_default_override() {
     let services = &[Service {
         name: "service_a".to_string(),
-        server: "/usr/sbin/service-a".to_string(),
-        port: 1234,
+        server: Some("/usr/sbin/service-a".to_string()),
+        port: Some(1234),
         uid: Some(50),
         ..DEFAULT_SERVICE.clone()
     }];
@@ -341,15 +727,15 @@ fn config_empty() {
 fn config_multi() {
     let service_a = Service {
         name: "service_a".to_string(),
-        server: "/usr/sbin/service-a".to_string(),
-        port: 1234,
+        server: Some("/usr/sbin/service-a".to_string()),
+        port: Some(1234),
         uid: Some(42),
         ..DEFAULT_SERVICE.clone()
     };
     let service_b = Service {
         name: "service_b".to_string(),
-        server: "/usr/sbin/service-b".to_string(),
-        port: 5678,
+        server: Some("/usr/sbin/service-b".to_string()),
+        port: Some(5678),
         uid: Some(0),
         ..DEFAULT_SERVICE.clone()
     };
@@ -365,7 +751,7 @@ fn config_multi() {
             .services(),
         &[
             Service {
-                port: 39847,
+                port: Some(39847),
                 ..service_a.clone()
             },
             service_b.clone()
@@ -454,6 +840,50 @@ fn config_invalid_option() {
         crate::Error::OptionValueParse { context: _, option } => assert_eq!(&option, "uid"),
         _ => panic!("wrong error: {}", err),
     }
+
+    let err = parse_config_str(FAIL_BAD_OPTION_RLIMIT).unwrap_err();
+    match err {
+        crate::Error::OptionValueParse { context: _, option } => {
+            assert_eq!(&option, "rlimit_as")
+        }
+        _ => panic!("wrong error: {}", err),
+    }
+}
+
+#[test]
+fn config_process_attributes() {
+    let config = parse_config_str(PASS_PROCESS_ATTRIBUTES).unwrap();
+    let service = &config.services()[0];
+    assert_eq!(service.nice, Some(Nice(10)));
+    assert_eq!(service.ioprio_class, Some(IoprioClass::Idle));
+    assert_eq!(service.ioprio, None);
+    assert_eq!(service.cpu_affinity, Some(CpuList(vec![0, 1, 3])));
+    assert_eq!(service.oom_score_adj, Some(OomScoreAdj(500)));
+    assert_eq!(service.umask, Some(Umask(0o027)));
+    assert_eq!(service.working_directory, Some(PathBuf::from("/home/ftp")));
+    assert_eq!(service.chroot, Some(PathBuf::from("/srv/jail")));
+}
+
+#[test]
+fn config_stderr() {
+    let config = parse_config_str(PASS_STDERR).unwrap();
+    let services = config.services();
+    assert_eq!(services[0].stderr, Some(StderrMode::Log));
+    assert_eq!(
+        services[1].stderr,
+        Some(StderrMode::File(PathBuf::from("/var/log/service-b.err")))
+    );
+}
+
+#[test]
+fn config_rlimits() {
+    let config = parse_config_str(PASS_RLIMITS).unwrap();
+    let service = &config.services()[0];
+    assert_eq!(service.rlimit_as, Some(RlimitSize(Some(64 << 20))));
+    assert_eq!(service.rlimit_cpu, Some(RlimitTime(Some(30))));
+    assert_eq!(service.rlimit_files, Some(Limit(None)));
+    assert_eq!(service.rlimit_core, Some(RlimitSize(Some(0))));
+    assert_eq!(service.rlimit_stack, None);
 }
 
 #[test]
@@ -494,4 +924,262 @@ fn mismatch_inet_type() {
     }
 }
 
+#[test]
+fn config_unix() {
+    let config = parse_config_str(PASS_UNIX).unwrap();
+    let services = config.services();
+    assert_eq!(
+        services,
+        &[
+            Service {
+                name: "service_a".to_string(),
+                server: Some("server".to_string()),
+                socket_type: SocketType::Unix,
+                listen_path: Some(UnixAddr::Path("/run/service-a.sock".into())),
+                ..DEFAULT_SERVICE.clone()
+            },
+            Service {
+                name: "service_b".to_string(),
+                server: Some("server".to_string()),
+                listen_path: Some(UnixAddr::Path("/run/service-b.sock".into())),
+                ..DEFAULT_SERVICE.clone()
+            },
+        ]
+    );
+    assert_eq!(services[0].effective_socket_type(), SocketType::Unix);
+    assert_eq!(services[1].effective_socket_type(), SocketType::Unix);
+
+    let config = parse_config_str(PASS_UNIX_DGRAM).unwrap();
+    let services = config.services();
+    assert_eq!(
+        services[0].listen_path,
+        Some(UnixAddr::Abstract("service-a".to_string()))
+    );
+    assert_eq!(services[0].effective_socket_type(), SocketType::UnixDgram);
+    assert_eq!(services[1].effective_socket_type(), SocketType::UnixDgram);
+}
+
+#[test]
+fn config_socket_type_requirements() {
+    let err = parse_config_str(FAIL_UNIX_MISSING_PATH).unwrap_err();
+    match err {
+        crate::Error::MissingRequiredOption { option, .. } => assert_eq!(&option, "listen_path"),
+        _ => panic!("wrong error: {}", err),
+    }
+
+    let err = parse_config_str(FAIL_MISSING_PORT).unwrap_err();
+    match err {
+        crate::Error::MissingRequiredOption { option, .. } => assert_eq!(&option, "port"),
+        _ => panic!("wrong error: {}", err),
+    }
+
+    let err = parse_config_str(FAIL_UNIX_WITH_PORT).unwrap_err();
+    match err {
+        crate::Error::InvalidService { service, .. } => assert_eq!(&service, "service_a"),
+        _ => panic!("wrong error: {}", err),
+    }
+}
+
+#[test]
+fn config_wait() {
+    let config = parse_config_str(PASS_WAIT).unwrap();
+    let wait_modes: Vec<bool> = config
+        .services()
+        .iter()
+        .map(|service| service.wait_mode())
+        .collect();
+    assert_eq!(wait_modes, &[true, false, true]);
+
+    let err = parse_config_str(FAIL_DGRAM_NO_WAIT).unwrap_err();
+    match err {
+        crate::Error::InvalidService { service, .. } => assert_eq!(&service, "service_a"),
+        _ => panic!("wrong error: {}", err),
+    }
+}
+
+#[test]
+fn config_internal() {
+    let config = parse_config_str(PASS_INTERNAL).unwrap();
+    let internal_services: Vec<Option<InternalService>> = config
+        .services()
+        .iter()
+        .map(|service| service.internal_service())
+        .collect();
+    assert_eq!(
+        internal_services,
+        &[Some(InternalService::Echo), Some(InternalService::Chargen)]
+    );
+    assert_eq!(config.services()[0].server, None);
+
+    for config in [FAIL_INTERNAL_UNKNOWN, FAIL_INTERNAL_WAIT].iter() {
+        let err = parse_config_str(config).unwrap_err();
+        match err {
+            crate::Error::InvalidService { .. } => {}
+            _ => panic!("wrong error: {}", err),
+        }
+    }
+}
+
+#[test]
+fn config_tcpmux() {
+    let config = parse_config_str(PASS_TCPMUX).unwrap();
+    let services = config.services();
+    assert_eq!(
+        services[0].internal_service(),
+        Some(InternalService::Tcpmux)
+    );
+    assert!(!services[0].is_tcpmux());
+    assert!(services[1].is_tcpmux());
+    assert_eq!(services[1].r#type, Some(ServiceType::TcpmuxPlus));
+    assert_eq!(services[1].port, None);
+
+    let err = parse_config_str(FAIL_TCPMUX_UDP).unwrap_err();
+    match err {
+        crate::Error::InvalidService { .. } => {}
+        _ => panic!("wrong error: {}", err),
+    }
+    let err = parse_config_str(FAIL_TCPMUX_NO_SERVER).unwrap_err();
+    match err {
+        crate::Error::MissingRequiredOption { .. } => {}
+        _ => panic!("wrong error: {}", err),
+    }
+}
+
+#[test]
+fn config_redirect() {
+    let config = parse_config_str(PASS_REDIRECT).unwrap();
+    assert_eq!(
+        config.services()[0].redirect,
+        Some(Redirect {
+            host: "db.internal".to_string(),
+            port: 5432,
+        })
+    );
+    assert_eq!(config.services()[0].server, None);
+
+    for config in [FAIL_REDIRECT_SERVER, FAIL_REDIRECT_UDP].iter() {
+        let err = parse_config_str(config).unwrap_err();
+        match err {
+            crate::Error::InvalidService { .. } => {}
+            _ => panic!("wrong error: {}", err),
+        }
+    }
+}
+
+#[test]
+fn config_proxy_protocol() {
+    let config = parse_config_str(PASS_PROXY_PROTOCOL).unwrap();
+    assert_eq!(config.services()[0].proxy_protocol, Some(ProxyProtocol::V2));
+
+    let err = parse_config_str(FAIL_PROXY_PROTOCOL_WAIT).unwrap_err();
+    match err {
+        crate::Error::InvalidService { .. } => {}
+        _ => panic!("wrong error: {}", err),
+    }
+}
+
+#[test]
+fn config_user() {
+    let config = parse_config_str(PASS_USER).unwrap();
+    let accounts: Vec<(Option<Account>, Option<Account>)> = config
+        .services()
+        .iter()
+        .map(|service| (service.run_as_user(), service.group.clone()))
+        .collect();
+    assert_eq!(
+        accounts,
+        &[
+            (
+                Some(Account::Name("nobody".to_string())),
+                Some(Account::Name("nogroup".to_string()))
+            ),
+            (Some(Account::Id(1000)), Some(Account::Id(100))),
+        ]
+    );
+
+    let err = parse_config_str(FAIL_USER_AND_UID).unwrap_err();
+    match err {
+        crate::Error::InvalidService { service, .. } => assert_eq!(&service, "service_a"),
+        _ => panic!("wrong error: {}", err),
+    }
+}
+
+#[test]
+fn config_instances() {
+    let config = parse_config_str(PASS_INSTANCES).unwrap();
+    let limits: Vec<(Limit, LimitAction)> = config
+        .services()
+        .iter()
+        .map(|service| (service.instances, service.instances_action))
+        .collect();
+    assert_eq!(
+        limits,
+        &[
+            (Limit(Some(10)), LimitAction::Pause),
+            (Limit(None), LimitAction::Pause),
+            (Limit(Some(2)), LimitAction::Reject),
+        ]
+    );
+    assert_eq!(config.services()[0].per_source, Limit(None));
+    assert_eq!(config.services()[2].per_source, Limit(Some(1)));
+}
+
+#[test]
+fn config_access() {
+    let config = parse_config_str(PASS_ACCESS).unwrap();
+    let services = config.services();
+    assert_eq!(
+        services[0].only_from,
+        Some(AddrList(vec![
+            AddrPattern::Net {
+                addr: "10.0.0.0".parse().unwrap(),
+                prefix_len: 8
+            },
+            AddrPattern::Net {
+                addr: "::1".parse().unwrap(),
+                prefix_len: 128
+            },
+            AddrPattern::Host("localhost".to_string()),
+        ]))
+    );
+    assert_eq!(
+        services[0].no_access,
+        Some(AddrList(vec![AddrPattern::Net {
+            addr: "192.0.2.0".parse().unwrap(),
+            prefix_len: 24
+        }]))
+    );
+    assert_eq!(services[1].only_from, None);
+    assert_eq!(
+        services[1].no_access,
+        Some(AddrList(vec![AddrPattern::Net {
+            addr: "10.1.2.3".parse().unwrap(),
+            prefix_len: 32
+        }]))
+    );
+    assert_eq!(
+        services[0].access_times,
+        Some(AccessTimes(vec![
+            TimeWindow {
+                start: 8 * 60,
+                end: 12 * 60
+            },
+            TimeWindow {
+                start: 13 * 60,
+                end: 18 * 60
+            },
+        ]))
+    );
+    assert_eq!(services[0].access_times_action, LimitAction::Pause);
+    assert_eq!(services[1].access_times, None);
+    assert_eq!(services[1].access_times_action, LimitAction::Reject);
+    assert_eq!(services[0].tcp_wrappers, None);
+    assert_eq!(services[1].tcp_wrappers, Some(YesNo(true)));
+    assert_eq!(
+        services[1].hosts_allow,
+        Some(PathBuf::from("/tmp/hosts.allow"))
+    );
+    assert_eq!(services[1].hosts_deny, None);
+}
+
 // todo(tmfink): test re-used ports
@@ -28,13 +28,13 @@ pub enum Error {
     },
 
     #[error("failed to parse config")]
-    Parse(#[from] PestError<Rule>),
+    Parse(#[source] Box<PestError<Rule>>),
 
     #[error("failed to parse value")]
     OptionValueParse {
         option: String,
         #[source]
-        context: PestError<Rule>,
+        context: Box<PestError<Rule>>,
     },
 
     #[error("missing required option {option:?} for service {service:?}")]
@@ -42,23 +42,40 @@ pub enum Error {
         option: String,
         service: String,
         #[source]
-        context: PestError<Rule>,
+        context: Box<PestError<Rule>>,
     },
 
     #[error("duplicate service {service:?}")]
     DuplicateService {
         service: String,
         #[source]
-        context: PestError<Rule>,
+        context: Box<PestError<Rule>>,
     },
 
     #[error("duplicate option {option:?}")]
     DuplicateOption {
         option: String,
         #[source]
-        context: PestError<Rule>,
+        context: Box<PestError<Rule>>,
     },
 
+    #[error("invalid service {service:?}")]
+    InvalidService {
+        service: String,
+        #[source]
+        context: Box<PestError<Rule>>,
+    },
+
+    #[error("unknown {kind} {account:?} for service {service:?}")]
+    UnknownAccount {
+        kind: &'static str,
+        account: String,
+        service: String,
+    },
+
+    #[error("user {user} of service {service:?} has no passwd entry, so \"group\" must be set")]
+    NoPrimaryGroup { user: u32, service: String },
+
     #[error("expected {expected_type} address, found {addr} for service {service_name:?}")]
     InetVersionAddressMismatch {
         expected_type: InetType,
@@ -77,7 +94,7 @@ impl Error {
         let context = custom_pest_error(message, value_pair.as_span());
         Self::OptionValueParse {
             option: option.to_string(),
-            context,
+            context: Box::new(context),
         }
     }
 
@@ -95,7 +112,7 @@ impl Error {
         Self::MissingRequiredOption {
             option: option.to_string(),
             service: service_name.to_string(),
-            context,
+            context: Box::new(context),
         }
     }
 
@@ -104,7 +121,19 @@ impl Error {
         let context = custom_pest_error(message, service_pair.as_span());
         Self::DuplicateService {
             service: service_name.to_string(),
-            context,
+            context: Box::new(context),
+        }
+    }
+
+    pub(crate) fn invalid_service(
+        service_name: &str,
+        service_pair: &Pair<Rule>,
+        message: impl Into<String>,
+    ) -> Self {
+        let context = custom_pest_error(message.into(), service_pair.as_span());
+        Self::InvalidService {
+            service: service_name.to_string(),
+            context: Box::new(context),
         }
     }
 
@@ -113,17 +142,17 @@ impl Error {
         let context = custom_pest_error(message, option_pair.as_span());
         Self::DuplicateOption {
             option: option_name.to_string(),
-            context,
+            context: Box::new(context),
         }
     }
 
     /// Add path context to Pest errors
     pub(crate) fn with_path(self, path: &str) -> Self {
         match self {
-            Self::Parse(pest_err) => Self::Parse(pest_err.with_path(path)),
+            Self::Parse(pest_err) => Self::Parse(Box::new(pest_err.with_path(path))),
             Self::OptionValueParse { option, context } => Self::OptionValueParse {
                 option,
-                context: context.with_path(path),
+                context: Box::new(context.with_path(path)),
             },
             Self::MissingRequiredOption {
                 option,
@@ -132,23 +161,44 @@ impl Error {
             } => Self::MissingRequiredOption {
                 option,
                 service,
-                context: context.with_path(path),
+                context: Box::new(context.with_path(path)),
             },
             Self::DuplicateService { service, context } => Self::DuplicateService {
                 service,
-                context: context.with_path(path),
+                context: Box::new(context.with_path(path)),
             },
             Self::DuplicateOption { option, context } => Self::DuplicateOption {
                 option,
-                context: context.with_path(path),
+                context: Box::new(context.with_path(path)),
+            },
+            Self::InvalidService { service, context } => Self::InvalidService {
+                service,
+                context: Box::new(context.with_path(path)),
             },
             _ => self,
         }
     }
 }
 
+impl From<PestError<Rule>> for Error {
+    fn from(err: PestError<Rule>) -> Self {
+        Self::Parse(Box::new(err))
+    }
+}
+
 pub type Result<T> = std::result::Result<T, Error>;
 
+/// Format an error with all of its sources, for logging
+pub(crate) fn error_chain(err: &dyn std::error::Error) -> String {
+    let mut message = err.to_string();
+    let mut source = err.source();
+    while let Some(err) = source {
+        message.push_str(&format!(": {}", err));
+        source = err.source();
+    }
+    message
+}
+
 pub(crate) fn custom_pest_error(message: String, span: Span) -> PestError<Rule> {
     PestError::new_from_span(PestErrorVariant::CustomError { message }, span)
 }
@@ -174,3 +224,25 @@ impl<T> StdIoErrorExt for std::io::Result<T> {
         self.map_err(|err| err.with_message(message))
     }
 }
+
+/// Convert a nix error to the equivalent [io::Error]
+pub(crate) fn nix_to_io(err: nix::Error) -> io::Error {
+    match err.as_errno() {
+        Some(errno) => io::Error::from_raw_os_error(errno as i32),
+        None => io::Error::other(err),
+    }
+}
+
+impl StdIoErrorExt for nix::Error {
+    type Into = Error;
+    fn with_message(self, message: impl Into<String>) -> Self::Into {
+        nix_to_io(self).with_message(message)
+    }
+}
+
+impl<T> StdIoErrorExt for nix::Result<T> {
+    type Into = Result<T>;
+    fn with_message(self, message: impl Into<String>) -> Self::Into {
+        self.map_err(|err| err.with_message(message))
+    }
+}
@@ -6,4 +6,4 @@ mod serve;
 pub mod service;
 
 pub use error::{Error, Result};
-pub use serve::serve_forever;
+pub use serve::{serve_forever, ServeOptions, Shutdown};
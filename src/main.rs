@@ -1,7 +1,11 @@
-use std::path::{Path, PathBuf};
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
 use clap::Clap;
 use log::*;
+use nix::sys::signal::Signal;
 
 use yinetd::config::parse::parse_config_file;
 
@@ -26,6 +30,26 @@ struct Opts {
     /// Exit after checking validity of config file
     #[clap(long = "check")]
     check_config: bool,
+
+    /// Signal sent to children on shutdown (e.g. SIGTERM, HUP)
+    #[clap(long = "shutdown-signal", default_value = "SIGTERM", parse(try_from_str = parse_signal))]
+    shutdown_signal: Signal,
+
+    /// Seconds to wait for children to exit on shutdown before killing them
+    #[clap(long = "shutdown-timeout", default_value = "10")]
+    shutdown_timeout_secs: u64,
+}
+
+/// Parse signal name, with or without the "SIG" prefix
+fn parse_signal(s: &str) -> Result<Signal, String> {
+    let name = s.to_uppercase();
+    let name = if name.starts_with("SIG") {
+        name
+    } else {
+        format!("SIG{}", name)
+    };
+    name.parse()
+        .map_err(|_| format!("invalid signal name {:?}", s))
 }
 
 fn init_logging(verbosity: i32) {
@@ -76,7 +100,16 @@ fn main() -> anyhow::Result<()> {
     for service in config.services() {
         println!("{:#?}", service);
     }
-    yinetd::serve_forever(config)?;
-
-    Ok(())
+    let serve_options = yinetd::ServeOptions {
+        config_path: Some(config_path),
+        shutdown_signal: opts.shutdown_signal,
+        shutdown_timeout: Duration::from_secs(opts.shutdown_timeout_secs),
+    };
+    let shutdown = yinetd::serve_forever(config, serve_options)?;
+    info!("Shut down after {}", shutdown.signal);
+
+    match shutdown.exit_code() {
+        0 => Ok(()),
+        code => std::process::exit(code),
+    }
 }
@@ -0,0 +1,298 @@
+use std::{collections::HashMap, fs, net::IpAddr, path::Path};
+
+use log::warn;
+
+use crate::{
+    config::{max_prefix_len, AddrList, AddrPattern},
+    service::Service,
+};
+
+pub(crate) const HOSTS_PATH: &str = "/etc/hosts";
+
+/// Host name to address mapping from a hosts(5) file
+#[derive(Debug, Default)]
+pub(crate) struct Hosts {
+    addrs: HashMap<String, Vec<IpAddr>>,
+    /// Canonical (first) name of each address
+    names: HashMap<IpAddr, String>,
+}
+
+impl Hosts {
+    pub(crate) fn parse(contents: &str) -> Self {
+        let mut hosts = Self::default();
+        for line in contents.lines() {
+            let line = line.split('#').next().unwrap_or_default();
+            let mut fields = line.split_whitespace();
+            let addr: IpAddr = match fields.next().map(str::parse) {
+                Some(Ok(addr)) => addr,
+                _ => continue,
+            };
+            for name in fields {
+                let name = name.to_lowercase();
+                hosts.names.entry(addr).or_insert_with(|| name.clone());
+                hosts.addrs.entry(name).or_default().push(addr);
+            }
+        }
+        hosts
+    }
+
+    pub(crate) fn load(path: &Path) -> Self {
+        match fs::read_to_string(path) {
+            Ok(contents) => Self::parse(&contents),
+            Err(err) => {
+                warn!("Failed to read hosts file {:?}: {}", path, err);
+                Self::default()
+            }
+        }
+    }
+
+    /// Addresses of host `name`
+    pub(crate) fn lookup(&self, name: &str) -> &[IpAddr] {
+        self.addrs
+            .get(&name.to_lowercase())
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
+
+    /// Canonical name of the host with address `addr`
+    pub(crate) fn name_of(&self, addr: IpAddr) -> Option<&str> {
+        self.names.get(&addr).map(String::as_str)
+    }
+}
+
+/// Network of addresses
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Net {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl Net {
+    pub(crate) fn new(addr: IpAddr, prefix_len: u8) -> Self {
+        Self { addr, prefix_len }
+    }
+
+    pub(crate) fn contains(&self, addr: IpAddr) -> bool {
+        match (self.addr, addr) {
+            (IpAddr::V4(net), IpAddr::V4(addr)) => {
+                prefix_matches(&net.octets(), &addr.octets(), self.prefix_len)
+            }
+            (IpAddr::V6(net), IpAddr::V6(addr)) => {
+                prefix_matches(&net.octets(), &addr.octets(), self.prefix_len)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn prefix_matches(net: &[u8], addr: &[u8], prefix_len: u8) -> bool {
+    let full_bytes = (prefix_len / 8) as usize;
+    let rest_bits = prefix_len % 8;
+    if net[..full_bytes] != addr[..full_bytes] {
+        return false;
+    }
+    if rest_bits == 0 {
+        return true;
+    }
+    let mask = !0u8 << (8 - rest_bits);
+    net[full_bytes] & mask == addr[full_bytes] & mask
+}
+
+/// Use the IPv4 address for IPv4-mapped IPv6 addresses (from dual-stack sockets)
+pub(crate) fn canonical_addr(addr: IpAddr) -> IpAddr {
+    match addr {
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => IpAddr::V4(v4),
+            None => addr,
+        },
+        IpAddr::V4(_) => addr,
+    }
+}
+
+fn resolve(list: &AddrList, hosts: &Hosts) -> Vec<Net> {
+    let mut nets = Vec::new();
+    for pattern in list.0.iter() {
+        match pattern {
+            AddrPattern::Net { addr, prefix_len } => nets.push(Net {
+                addr: *addr,
+                prefix_len: *prefix_len,
+            }),
+            AddrPattern::Host(name) => {
+                let addrs = hosts.lookup(name);
+                if addrs.is_empty() {
+                    warn!("Host name {:?} not found in hosts file", name);
+                }
+                nets.extend(addrs.iter().map(|&addr| Net {
+                    addr,
+                    prefix_len: max_prefix_len(addr),
+                }));
+            }
+        }
+    }
+    nets
+}
+
+/// Prefix length of the most specific network in `nets` that contains `addr`
+fn best_match(nets: &[Net], addr: IpAddr) -> Option<u8> {
+    nets.iter()
+        .filter(|net| net.contains(addr))
+        .map(|net| net.prefix_len)
+        .max()
+}
+
+/// Host-based access control from the `only_from` and `no_access` options
+#[derive(Debug, Default)]
+pub(crate) struct AccessControl {
+    /// `None` if all clients are allowed
+    only_from: Option<Vec<Net>>,
+    no_access: Vec<Net>,
+    /// Hosts file, loaded once if the service needs to resolve host names
+    hosts: Hosts,
+}
+
+impl AccessControl {
+    pub(crate) fn new(service: &Service) -> Self {
+        let has_host = |list: &Option<AddrList>| {
+            list.iter()
+                .flat_map(|list| list.0.iter())
+                .any(|pattern| matches!(pattern, AddrPattern::Host(_)))
+        };
+        // The tcp wrappers rules may name hosts too
+        let hosts = if has_host(&service.only_from)
+            || has_host(&service.no_access)
+            || service.tcp_wrappers.unwrap_or_default().0
+        {
+            Hosts::load(Path::new(HOSTS_PATH))
+        } else {
+            Hosts::default()
+        };
+        let mut access = Self::with_hosts(
+            service.only_from.as_ref(),
+            service.no_access.as_ref(),
+            &hosts,
+        );
+        access.hosts = hosts;
+        access
+    }
+
+    pub(crate) fn with_hosts(
+        only_from: Option<&AddrList>,
+        no_access: Option<&AddrList>,
+        hosts: &Hosts,
+    ) -> Self {
+        Self {
+            only_from: only_from.map(|list| resolve(list, hosts)),
+            no_access: no_access
+                .map(|list| resolve(list, hosts))
+                .unwrap_or_default(),
+            hosts: Hosts::default(),
+        }
+    }
+
+    /// Hosts file of the service, empty unless it names hosts
+    pub(crate) fn hosts(&self) -> &Hosts {
+        &self.hosts
+    }
+
+    /// Whether a client with address `addr` may connect.
+    ///
+    /// Like xinetd, when the address matches both lists, the more specific match wins; ties deny.
+    pub(crate) fn allows(&self, addr: IpAddr) -> bool {
+        let addr = canonical_addr(addr);
+        let denied = best_match(&self.no_access, addr);
+        let allowed = match &self.only_from {
+            Some(only_from) => match best_match(only_from, addr) {
+                Some(prefix_len) => prefix_len,
+                None => return false,
+            },
+            None => return denied.is_none(),
+        };
+        match denied {
+            Some(denied) => allowed > denied,
+            None => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const HOSTS: &str = "
+127.0.0.1   localhost
+::1         localhost ip6-localhost
+10.1.2.3    build.example.com build # build server
+";
+
+    fn access(only_from: Option<&str>, no_access: Option<&str>) -> AccessControl {
+        AccessControl::with_hosts(
+            only_from.map(|list| list.parse().unwrap()).as_ref(),
+            no_access.map(|list| list.parse().unwrap()).as_ref(),
+            &Hosts::parse(HOSTS),
+        )
+    }
+
+    fn addr(addr: &str) -> IpAddr {
+        addr.parse().unwrap()
+    }
+
+    #[test]
+    fn hosts() {
+        let hosts = Hosts::parse(HOSTS);
+        assert_eq!(hosts.lookup("localhost"), &[addr("127.0.0.1"), addr("::1")]);
+        assert_eq!(hosts.lookup("BUILD"), &[addr("10.1.2.3")]);
+        assert!(hosts.lookup("server").is_empty());
+        assert_eq!(hosts.name_of(addr("::1")), Some("localhost"));
+        assert_eq!(hosts.name_of(addr("10.1.2.3")), Some("build.example.com"));
+        assert_eq!(hosts.name_of(addr("10.1.2.4")), None);
+    }
+
+    #[test]
+    fn no_rules() {
+        assert!(access(None, None).allows(addr("192.0.2.1")));
+    }
+
+    #[test]
+    fn only_from() {
+        let access = access(Some("10.0.0.0/8 2001:db8::/32 localhost"), None);
+        assert!(access.allows(addr("10.200.0.1")));
+        assert!(access.allows(addr("2001:db8::1")));
+        assert!(access.allows(addr("127.0.0.1")));
+        assert!(access.allows(addr("::ffff:10.0.0.1")));
+        assert!(!access.allows(addr("11.0.0.1")));
+        assert!(!access.allows(addr("2001:db9::1")));
+
+        assert!(!self::access(Some(""), None).allows(addr("10.0.0.1")));
+        assert!(self::access(Some("0.0.0.0"), None).allows(addr("192.0.2.1")));
+    }
+
+    #[test]
+    fn no_access() {
+        let access = access(None, Some("192.168.0.0/16 build"));
+        assert!(!access.allows(addr("192.168.3.4")));
+        assert!(!access.allows(addr("10.1.2.3")));
+        assert!(access.allows(addr("10.1.2.4")));
+    }
+
+    #[test]
+    fn most_specific_match() {
+        let access = access(Some("10.0.0.0/8 10.1.2.3"), Some("10.1.0.0/16"));
+        assert!(access.allows(addr("10.2.0.1")));
+        assert!(!access.allows(addr("10.1.0.1")));
+        assert!(access.allows(addr("10.1.2.3")));
+
+        let access = self::access(Some("10.0.0.0/8"), Some("10.0.0.0/8"));
+        assert!(!access.allows(addr("10.0.0.1")));
+    }
+
+    #[test]
+    fn prefix_lengths() {
+        let net = Net {
+            addr: addr("192.168.128.0"),
+            prefix_len: 17,
+        };
+        assert!(net.contains(addr("192.168.255.255")));
+        assert!(!net.contains(addr("192.168.127.255")));
+        assert!(!net.contains(addr("::1")));
+    }
+}
@@ -0,0 +1,139 @@
+use std::{fmt::Debug, time::Duration};
+
+use crate::config::{AccessTimes, TimeWindow};
+
+const SECS_PER_DAY: u32 = 24 * 60 * 60;
+
+/// Source of the local time of day
+pub(crate) trait Clock: Debug {
+    /// Seconds since local midnight
+    fn secs_since_midnight(&self) -> u32;
+}
+
+/// Local time of the system time zone
+#[derive(Debug)]
+pub(crate) struct LocalClock;
+
+impl Clock for LocalClock {
+    fn secs_since_midnight(&self) -> u32 {
+        let now = unsafe { libc::time(std::ptr::null_mut()) };
+        let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+        if unsafe { libc::localtime_r(&now, &mut tm) }.is_null() {
+            // Fall back to UTC
+            return (now.rem_euclid(SECS_PER_DAY as libc::time_t)) as u32;
+        }
+        (tm.tm_hour * 3600 + tm.tm_min * 60 + tm.tm_sec) as u32
+    }
+}
+
+/// `access_times` of a service, checked against a clock
+#[derive(Debug)]
+pub(crate) struct AccessWindows {
+    windows: Vec<TimeWindow>,
+    clock: Box<dyn Clock>,
+}
+
+impl AccessWindows {
+    pub(crate) fn new(access_times: &AccessTimes, clock: Box<dyn Clock>) -> Self {
+        Self {
+            windows: access_times.0.clone(),
+            clock,
+        }
+    }
+
+    /// Whether the current time is within one of the windows
+    pub(crate) fn is_open(&self) -> bool {
+        let minute = (self.clock.secs_since_midnight() / 60) as u16;
+        self.windows.iter().any(|window| window.contains(minute))
+    }
+
+    /// Time until a window opens or closes
+    pub(crate) fn until_change(&self) -> Duration {
+        let now = self.clock.secs_since_midnight();
+        let secs = self
+            .windows
+            .iter()
+            .flat_map(|window| {
+                // The end minute is part of the window
+                let end = (window.end + 1) % TimeWindow::MINUTES_PER_DAY;
+                [window.start, end]
+            })
+            .map(|boundary| {
+                let secs = (boundary as u32 * 60 + SECS_PER_DAY - now) % SECS_PER_DAY;
+                if secs == 0 {
+                    SECS_PER_DAY
+                } else {
+                    secs
+                }
+            })
+            .min()
+            .unwrap_or(SECS_PER_DAY);
+        Duration::from_secs(secs as u64)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{cell::Cell, rc::Rc};
+
+    use super::*;
+
+    #[derive(Debug)]
+    struct TestClock(Rc<Cell<u32>>);
+
+    impl Clock for TestClock {
+        fn secs_since_midnight(&self) -> u32 {
+            self.0.get()
+        }
+    }
+
+    fn windows(access_times: &str) -> (AccessWindows, Rc<Cell<u32>>) {
+        let now = Rc::new(Cell::new(0));
+        let windows = AccessWindows::new(
+            &access_times.parse().unwrap(),
+            Box::new(TestClock(now.clone())),
+        );
+        (windows, now)
+    }
+
+    fn time(hours: u32, minutes: u32, secs: u32) -> u32 {
+        hours * 3600 + minutes * 60 + secs
+    }
+
+    #[test]
+    fn is_open() {
+        let (windows, now) = windows("08:00-12:00 13:00-18:00");
+        now.set(time(7, 59, 59));
+        assert!(!windows.is_open());
+        now.set(time(8, 0, 0));
+        assert!(windows.is_open());
+        now.set(time(12, 0, 59));
+        assert!(windows.is_open());
+        now.set(time(12, 1, 0));
+        assert!(!windows.is_open());
+        now.set(time(15, 0, 0));
+        assert!(windows.is_open());
+    }
+
+    #[test]
+    fn until_change() {
+        let (windows, now) = windows("08:00-12:00 22:00-02:00");
+        now.set(time(7, 0, 0));
+        assert_eq!(windows.until_change(), Duration::from_secs(3600));
+        now.set(time(8, 0, 0));
+        assert_eq!(
+            windows.until_change(),
+            Duration::from_secs(time(4, 1, 0) as u64)
+        );
+        now.set(time(12, 30, 30));
+        assert_eq!(
+            windows.until_change(),
+            Duration::from_secs(time(9, 29, 30) as u64)
+        );
+        now.set(time(23, 0, 0));
+        assert_eq!(
+            windows.until_change(),
+            Duration::from_secs(time(3, 1, 0) as u64)
+        );
+    }
+}
@@ -0,0 +1,120 @@
+use std::{
+    fmt::{self, Display},
+    io::{self, Read, Write},
+    net::SocketAddr,
+    os::unix::io::{AsRawFd, RawFd},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use mio::{
+    event::Source,
+    net::{TcpStream, UnixStream},
+    Interest, Registry, Token,
+};
+use nix::sys::socket::{recv, MsgFlags};
+
+use crate::error::nix_to_io;
+
+/// Accepted stream of a connection-oriented service
+pub(crate) enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl AsRawFd for Stream {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            Self::Tcp(stream) => stream.as_raw_fd(),
+            Self::Unix(stream) => stream.as_raw_fd(),
+        }
+    }
+}
+
+impl Stream {
+    /// Receive data without removing it from the socket queue
+    pub(crate) fn peek(&self, buf: &mut [u8]) -> io::Result<usize> {
+        recv(self.as_raw_fd(), buf, MsgFlags::MSG_PEEK).map_err(nix_to_io)
+    }
+
+    fn source(&mut self) -> &mut dyn Source {
+        match self {
+            Self::Tcp(stream) => stream,
+            Self::Unix(stream) => stream,
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Self::Tcp(stream) => stream.read(buf),
+            Self::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Tcp(stream) => stream.write(buf),
+            Self::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Tcp(stream) => stream.flush(),
+            Self::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+impl Source for Stream {
+    fn register(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        self.source().register(registry, token, interests)
+    }
+
+    fn reregister(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        self.source().reregister(registry, token, interests)
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        self.source().deregister(registry)
+    }
+}
+
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Unique (for this yinetd process) ID of a new connection or "wait" server
+pub(crate) fn next_connection_id() -> u64 {
+    NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Accepted connection
+pub(crate) struct Connection {
+    pub(crate) id: u64,
+    pub(crate) stream: Stream,
+    /// Peer address, for inet connections
+    pub(crate) peer_addr: Option<SocketAddr>,
+    /// Local address the peer connected to, for inet connections
+    pub(crate) local_addr: Option<SocketAddr>,
+}
+
+impl Display for Connection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.peer_addr {
+            Some(peer_addr) => write!(f, "{}", peer_addr),
+            None => write!(f, "Unix peer"),
+        }
+    }
+}
@@ -0,0 +1,162 @@
+use std::{
+    ffi::CString,
+    fs, io,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+};
+
+use crate::{error::StdIoErrorExt, service::Service};
+
+/// Root, working directory and umask of a service (`chroot`, `working_directory`, `umask`).
+///
+/// Directories are checked in the parent, so a bad configuration is reported as an [Error]
+/// instead of as a failed exec.
+///
+/// [Error]: crate::Error
+#[derive(Debug, Clone)]
+pub(crate) struct Directories {
+    chroot: Option<CString>,
+    working_directory: Option<CString>,
+    umask: Option<libc::mode_t>,
+}
+
+impl Directories {
+    /// Directories of `service`; `None` if the server keeps ours
+    pub(crate) fn for_service(service: &Service) -> crate::Result<Option<Self>> {
+        if service.chroot.is_none()
+            && service.working_directory.is_none()
+            && service.umask.is_none()
+        {
+            return Ok(None);
+        }
+
+        let chroot = match &service.chroot {
+            Some(chroot) => Some(check_dir(chroot).with_message(format!(
+                "invalid chroot {:?} for service {:?}",
+                chroot, service.name
+            ))?),
+            None => None,
+        };
+        // Inside the chroot, working directories are relative to the new root
+        let working_directory = match (&service.chroot, &service.working_directory) {
+            (Some(_), None) => Some(PathBuf::from("/")),
+            (_, working_directory) => working_directory.clone(),
+        };
+        let working_directory = match working_directory {
+            Some(working_directory) => {
+                let outside_path = match &service.chroot {
+                    Some(chroot) => chroot.join(
+                        working_directory
+                            .strip_prefix("/")
+                            .unwrap_or(&working_directory),
+                    ),
+                    None => working_directory.clone(),
+                };
+                let checked = check_dir(&outside_path)
+                    .and_then(|_| c_path(&working_directory))
+                    .with_message(format!(
+                        "invalid working_directory {:?} for service {:?}",
+                        working_directory, service.name
+                    ))?;
+                Some(checked)
+            }
+            None => None,
+        };
+
+        Ok(Some(Self {
+            chroot,
+            working_directory,
+            umask: service.umask.map(|umask| umask.0 as libc::mode_t),
+        }))
+    }
+
+    /// Change root, then working directory, then umask. Called in the child before exec, before
+    /// dropping privileges.
+    pub(crate) fn apply(&self) -> io::Result<()> {
+        if let Some(chroot) = &self.chroot {
+            if unsafe { libc::chroot(chroot.as_ptr()) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        if let Some(working_directory) = &self.working_directory {
+            if unsafe { libc::chdir(working_directory.as_ptr()) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        if let Some(umask) = self.umask {
+            unsafe { libc::umask(umask) };
+        }
+        Ok(())
+    }
+}
+
+/// Check that `path` is a directory, and convert it for system calls
+fn check_dir(path: &Path) -> io::Result<CString> {
+    if !fs::metadata(path)?.is_dir() {
+        return Err(io::Error::from_raw_os_error(libc::ENOTDIR));
+    }
+    c_path(path)
+}
+
+fn c_path(path: &Path) -> io::Result<CString> {
+    CString::new(path.as_os_str().as_bytes())
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::parse::parse_test_service;
+
+    fn directories(options: &str) -> crate::Result<Option<Directories>> {
+        Directories::for_service(&parse_test_service(options))
+    }
+
+    fn c_str(path: &str) -> Option<CString> {
+        Some(CString::new(path).unwrap())
+    }
+
+    #[test]
+    fn for_service() {
+        let jail = std::env::temp_dir().join(format!("yinetd-jail-{}", std::process::id()));
+        fs::create_dir_all(jail.join("srv")).unwrap();
+        fs::write(jail.join("file"), "").unwrap();
+        let jail_path = jail.to_str().unwrap();
+        let chroot = |options: &str| directories(&format!("chroot = {}\n {}", jail_path, options));
+
+        assert!(directories("").unwrap().is_none());
+        let umask_only = directories("umask = 027").unwrap().unwrap();
+        assert_eq!(
+            (
+                umask_only.chroot,
+                umask_only.working_directory,
+                umask_only.umask
+            ),
+            (None, None, Some(0o027))
+        );
+
+        // Inside the chroot, the working directory defaults to its root
+        let jailed = chroot("").unwrap().unwrap();
+        assert_eq!(jailed.chroot, c_str(jail_path));
+        assert_eq!(jailed.working_directory, c_str("/"));
+        let jailed = chroot("working_directory = /srv").unwrap().unwrap();
+        assert_eq!(jailed.working_directory, c_str("/srv"));
+
+        // Missing directories and files are rejected, and the working directory of a chroot is
+        // checked inside it
+        assert!(chroot("working_directory = /file").is_err());
+        assert!(chroot(&format!("working_directory = {}", jail_path)).is_err());
+        assert!(directories(&format!("chroot = {}/missing", jail_path)).is_err());
+        assert!(directories(&format!("chroot = {}/file", jail_path)).is_err());
+        assert!(directories(&format!("working_directory = {}/missing", jail_path)).is_err());
+        assert_eq!(
+            directories(&format!("working_directory = {}/srv", jail_path))
+                .unwrap()
+                .unwrap()
+                .working_directory,
+            c_str(&format!("{}/srv", jail_path))
+        );
+
+        fs::remove_dir_all(&jail).unwrap();
+    }
+}
@@ -0,0 +1,251 @@
+use std::{
+    collections::BTreeMap,
+    env,
+    ffi::{OsStr, OsString},
+    fs, io,
+    net::SocketAddr,
+    process,
+};
+
+use crate::{
+    config::{is_env_name, SocketType, YesNo},
+    error::StdIoErrorExt,
+    service::Service,
+};
+
+/// PATH of servers that do not set their own
+pub(crate) const DEFAULT_PATH: &str =
+    "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+
+/// Connection a server is spawned for, described to it by environment variables
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct ConnectionEnv {
+    /// See [next_connection_id](super::connection::next_connection_id)
+    pub(crate) id: u64,
+    pub(crate) remote_addr: Option<SocketAddr>,
+    pub(crate) local_addr: Option<SocketAddr>,
+}
+
+/// Environment of a child process, built from scratch instead of inherited
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Environment(BTreeMap<OsString, OsString>);
+
+impl Default for Environment {
+    /// Minimal environment
+    fn default() -> Self {
+        let mut vars = BTreeMap::new();
+        vars.insert(OsString::from("PATH"), OsString::from(DEFAULT_PATH));
+        Self(vars)
+    }
+}
+
+impl Environment {
+    /// Environment of a `service` server: `passenv` variables from our environment, then the
+    /// `env_file`, then `env`, then the connection variables unless `connection_env = no`
+    pub(crate) fn for_service(
+        service: &Service,
+        connection: &ConnectionEnv,
+    ) -> crate::Result<Self> {
+        let env_file = match &service.env_file {
+            Some(path) => {
+                let contents = fs::read_to_string(path)
+                    .and_then(|contents| parse_env_file(&contents))
+                    .with_message(format!(
+                        "failed to read env_file {:?} of service {:?}",
+                        path, service.name
+                    ))?;
+                Some(contents)
+            }
+            None => None,
+        };
+        let mut environment = Self::build(service, env::vars_os(), env_file);
+        if service.connection_env != Some(YesNo(false)) {
+            environment.add_connection(service, connection);
+        }
+        Ok(environment)
+    }
+
+    fn build(
+        service: &Service,
+        inherited: impl Iterator<Item = (OsString, OsString)>,
+        env_file: Option<Vec<(String, String)>>,
+    ) -> Self {
+        let mut environment = Self::default();
+        if let Some(passenv) = &service.passenv {
+            let inherited =
+                inherited.filter(|(key, _)| passenv.0.iter().any(|name| OsStr::new(name) == key));
+            environment.0.extend(inherited);
+        }
+        let assignments = env_file
+            .into_iter()
+            .flatten()
+            .chain(service.env.iter().flat_map(|env| env.0.iter().cloned()));
+        environment
+            .0
+            .extend(assignments.map(|(key, value)| (OsString::from(key), OsString::from(value))));
+        environment
+    }
+
+    /// Add ucspi-tcp style `PROTO`, `TCPREMOTEIP`, `TCPREMOTEPORT`, `TCPLOCALIP` and
+    /// `TCPLOCALPORT`, and `YINETD_SERVICE` and `YINETD_CONNECTION_ID`
+    fn add_connection(&mut self, service: &Service, connection: &ConnectionEnv) {
+        let proto = match service.effective_socket_type() {
+            SocketType::Tcp => "TCP",
+            SocketType::Udp => "UDP",
+            SocketType::Unix | SocketType::UnixDgram => "UNIX",
+        };
+        let mut set = |key: &str, value: String| {
+            self.0.insert(OsString::from(key), OsString::from(value));
+        };
+        set("PROTO", proto.to_string());
+        let addrs = [
+            ("REMOTE", connection.remote_addr),
+            ("LOCAL", connection.local_addr),
+        ];
+        for (end, addr) in addrs.iter() {
+            if let Some(addr) = addr {
+                set(&format!("{}{}IP", proto, end), addr.ip().to_string());
+                set(&format!("{}{}PORT", proto, end), addr.port().to_string());
+            }
+        }
+        set("YINETD_SERVICE", service.name.clone());
+        set(
+            "YINETD_CONNECTION_ID",
+            format!("{}-{}", process::id(), connection.id),
+        );
+    }
+
+    pub(crate) fn vars(&self) -> impl Iterator<Item = (&OsString, &OsString)> {
+        self.0.iter()
+    }
+}
+
+/// Parse "KEY=VALUE" lines, skipping blank lines and `#` comments. Values may be quoted.
+fn parse_env_file(contents: &str) -> io::Result<Vec<(String, String)>> {
+    let mut vars = Vec::new();
+    for (idx, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let (key, value) = match line.split_once('=') {
+            Some((key, value)) if is_env_name(key.trim()) => (key.trim(), value.trim()),
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("line {}: expected KEY=VALUE", idx + 1),
+                ))
+            }
+        };
+        let value = ['"', '\'']
+            .iter()
+            .find_map(|&quote| {
+                value
+                    .strip_prefix(quote)
+                    .and_then(|value| value.strip_suffix(quote))
+            })
+            .unwrap_or(value);
+        vars.push((key.to_string(), value.to_string()));
+    }
+    Ok(vars)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::{parse::parse_test_service, EnvNames};
+
+    fn vars(environment: &Environment) -> Vec<(&str, &str)> {
+        environment
+            .vars()
+            .map(|(key, value)| (key.to_str().unwrap(), value.to_str().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn env_file() {
+        let contents = "\
+# settings
+DB_HOST=db.example.com
+export DB_USER = 'app'
+DB_PASSWORD=\"p#ss word\"
+
+EMPTY=
+";
+        assert_eq!(
+            parse_env_file(contents).unwrap(),
+            vec![
+                ("DB_HOST".to_string(), "db.example.com".to_string()),
+                ("DB_USER".to_string(), "app".to_string()),
+                ("DB_PASSWORD".to_string(), "p#ss word".to_string()),
+                ("EMPTY".to_string(), "".to_string()),
+            ]
+        );
+        assert!(parse_env_file("not an assignment").is_err());
+    }
+
+    #[test]
+    fn precedence() {
+        let service = parse_test_service("passenv = LANG HOME\n env = LANG=C PATH=/opt/bin");
+        assert_eq!(
+            service.passenv,
+            Some(EnvNames(vec!["LANG".into(), "HOME".into()]))
+        );
+
+        let inherited = vec![
+            (OsString::from("HOME"), OsString::from("/root")),
+            (OsString::from("LANG"), OsString::from("en_US.UTF-8")),
+            (OsString::from("YINETD_LOG"), OsString::from("debug")),
+        ];
+        let env_file = vec![
+            ("HOME".to_string(), "/srv".to_string()),
+            ("TOKEN".to_string(), "secret".to_string()),
+        ];
+        let environment = Environment::build(&service, inherited.into_iter(), Some(env_file));
+        assert_eq!(
+            vars(&environment),
+            vec![
+                ("HOME", "/srv"),
+                ("LANG", "C"),
+                ("PATH", "/opt/bin"),
+                ("TOKEN", "secret"),
+            ]
+        );
+    }
+
+    #[test]
+    fn connection() {
+        let connection = ConnectionEnv {
+            id: 7,
+            remote_addr: Some("[2001:db8::1]:40000".parse().unwrap()),
+            local_addr: Some("192.0.2.1:23".parse().unwrap()),
+        };
+        let mut environment = Environment::default();
+        environment.add_connection(&parse_test_service(""), &connection);
+        let connection_id = format!("{}-7", process::id());
+        assert_eq!(
+            vars(&environment),
+            vec![
+                ("PATH", DEFAULT_PATH),
+                ("PROTO", "TCP"),
+                ("TCPLOCALIP", "192.0.2.1"),
+                ("TCPLOCALPORT", "23"),
+                ("TCPREMOTEIP", "2001:db8::1"),
+                ("TCPREMOTEPORT", "40000"),
+                ("YINETD_CONNECTION_ID", &connection_id),
+                ("YINETD_SERVICE", "s"),
+            ]
+        );
+    }
+
+    #[test]
+    fn minimal() {
+        let environment = Environment::build(
+            &parse_test_service(""),
+            vec![(OsString::from("YINETD_LOG"), OsString::from("debug"))].into_iter(),
+            None,
+        );
+        assert_eq!(vars(&environment), vec![("PATH", DEFAULT_PATH)]);
+    }
+}
@@ -0,0 +1,349 @@
+use std::{
+    io::{self, Read, Write},
+    net::IpAddr,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use log::debug;
+use mio::{Interest, Registry, Token};
+
+use super::{connection::Connection, ConnectionToken};
+use crate::config::InternalService;
+
+/// Seconds from the RFC 868 epoch (1900) to the Unix epoch (1970)
+const RFC_868_EPOCH_OFFSET: u64 = 2_208_988_800;
+
+const CHARGEN_LINE_LEN: usize = 72;
+/// Lines added to the chargen output whenever it was all written
+const CHARGEN_LINES_PER_WRITE: usize = 64;
+/// Full chargen lines that fit in a 512 byte datagram
+const CHARGEN_DATAGRAM_LINES: usize = 512 / (CHARGEN_LINE_LEN + 2);
+
+/// Echoed bytes buffered for a client that does not read; reading stops beyond this
+const MAX_ECHO_PENDING: usize = 64 * 1024;
+
+/// Bytes read and written per event, so one fast client cannot starve the others
+const MAX_TRANSFER_PER_EVENT: usize = 64 * 1024;
+
+const READ_BUF_LEN: usize = 16 * 1024;
+
+/// How long a TCPMUX client has to send the service name
+const TCPMUX_TIMEOUT: Duration = Duration::from_secs(10);
+/// Longest TCPMUX service name line
+const MAX_TCPMUX_LINE_LEN: usize = 256;
+
+/// Stream connection of an INTERNAL service, served by the event loop
+pub(crate) struct InternalConnection {
+    service: InternalService,
+    connection: Connection,
+    token: ConnectionToken,
+    /// Bytes not written yet
+    output: Vec<u8>,
+    /// Next chargen line
+    chargen_line: usize,
+    /// Whether the connection closes once `output` is written
+    closing: bool,
+    /// Time by which the client must have sent what the service waits for
+    deadline: Option<Instant>,
+}
+
+/// Connection of a TCPMUX client that asked for the service `name`
+pub(crate) struct TcpmuxRequest {
+    pub(crate) name: String,
+    pub(crate) connection: Connection,
+}
+
+/// State of an internal connection after an event
+pub(crate) enum Progress {
+    Open,
+    Closed,
+    /// A TCPMUX client asked for the service with this name
+    Tcpmux(String),
+}
+
+impl InternalConnection {
+    pub(crate) fn new(service: InternalService, connection: Connection) -> Self {
+        let now = SystemTime::now();
+        let output = match service {
+            InternalService::Daytime => daytime(now),
+            InternalService::Time => rfc868_time(now).to_vec(),
+            InternalService::Echo
+            | InternalService::Discard
+            | InternalService::Chargen
+            | InternalService::Tcpmux => Vec::new(),
+        };
+        let deadline = match service {
+            InternalService::Tcpmux => Some(Instant::now() + TCPMUX_TIMEOUT),
+            _ => None,
+        };
+        Self {
+            service,
+            token: ConnectionToken::new(),
+            connection,
+            output,
+            chargen_line: 0,
+            closing: matches!(service, InternalService::Daytime | InternalService::Time),
+            deadline,
+        }
+    }
+
+    pub(crate) fn token(&self) -> Token {
+        self.token.token()
+    }
+
+    pub(crate) fn peer_ip(&self) -> Option<IpAddr> {
+        self.connection.peer_addr.map(|peer_addr| peer_addr.ip())
+    }
+
+    /// Client of the connection, for logging
+    pub(crate) fn peer(&self) -> &Connection {
+        &self.connection
+    }
+
+    pub(crate) fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    pub(crate) fn into_connection(self) -> Connection {
+        self.connection
+    }
+
+    pub(crate) fn register(&mut self, registry: &Registry) -> io::Result<()> {
+        registry.register(
+            &mut self.connection.stream,
+            self.token.token(),
+            Interest::READABLE | Interest::WRITABLE,
+        )
+    }
+
+    pub(crate) fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        registry.deregister(&mut self.connection.stream)
+    }
+
+    /// Read and write until the connection would block
+    pub(crate) fn handle(&mut self, registry: &Registry) -> io::Result<Progress> {
+        if self.service == InternalService::Tcpmux {
+            return Ok(match self.read_tcpmux_line()? {
+                Some(name) => Progress::Tcpmux(name),
+                None => Progress::Open,
+            });
+        }
+
+        let mut buf = [0; READ_BUF_LEN];
+        let mut transferred = 0;
+        loop {
+            let written = self.write_output()?;
+            let read = match self.read_input(&mut buf)? {
+                Some(read) => read,
+                None => return Ok(Progress::Closed),
+            };
+            if self.closing && self.output.is_empty() {
+                return Ok(Progress::Closed);
+            }
+            if written == 0 && read == 0 {
+                return Ok(Progress::Open);
+            }
+
+            transferred += written + read;
+            if transferred >= MAX_TRANSFER_PER_EVENT {
+                // Events are edge-triggered: re-arm them, since the connection may not block
+                registry.reregister(
+                    &mut self.connection.stream,
+                    self.token.token(),
+                    Interest::READABLE | Interest::WRITABLE,
+                )?;
+                return Ok(Progress::Open);
+            }
+        }
+    }
+
+    /// Read the TCPMUX service name line, without reading past it: the rest is for the server.
+    ///
+    /// Returns `None` until the whole line arrived.
+    fn read_tcpmux_line(&mut self) -> io::Result<Option<String>> {
+        let mut buf = [0; MAX_TCPMUX_LINE_LEN];
+        let len = match self.connection.stream.peek(&mut buf) {
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(len) => len,
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        let end = match buf[..len].iter().position(|&byte| byte == b'\n') {
+            Some(end) => end,
+            None if len == buf.len() => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "TCPMUX service name is too long",
+                ))
+            }
+            None => return Ok(None),
+        };
+        // Already received, so this does not block
+        self.connection.stream.read_exact(&mut buf[..=end])?;
+        let name = String::from_utf8_lossy(&buf[..end]).trim().to_string();
+        Ok(Some(name))
+    }
+
+    /// Read what the client sent, if the service still reads.
+    ///
+    /// Returns the number of bytes read, or `None` if the connection should close.
+    fn read_input(&mut self, buf: &mut [u8]) -> io::Result<Option<usize>> {
+        let echo = self.service == InternalService::Echo;
+        if self.closing || (echo && self.output.len() >= MAX_ECHO_PENDING) {
+            return Ok(Some(0));
+        }
+        match self.connection.stream.read(buf) {
+            Ok(0) if echo => {
+                // Echo what is left, then close
+                self.closing = true;
+                Ok(Some(0))
+            }
+            Ok(0) => Ok(None),
+            Ok(len) => {
+                if echo {
+                    self.output.extend_from_slice(&buf[..len]);
+                }
+                Ok(Some(len))
+            }
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => Ok(Some(0)),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Write pending output; returns the number of bytes written
+    fn write_output(&mut self) -> io::Result<usize> {
+        if self.service == InternalService::Chargen && self.output.is_empty() {
+            for _ in 0..CHARGEN_LINES_PER_WRITE {
+                chargen_line(self.chargen_line, &mut self.output);
+                self.chargen_line += 1;
+            }
+        }
+        if self.output.is_empty() {
+            return Ok(0);
+        }
+        match self.connection.stream.write(&self.output) {
+            Ok(len) => {
+                self.output.drain(..len);
+                Ok(len)
+            }
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => Ok(0),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+/// Reply of an INTERNAL datagram service to a datagram, if it replies
+pub(crate) fn datagram_reply(service: InternalService, request: &[u8]) -> Option<Vec<u8>> {
+    match service {
+        InternalService::Echo => Some(request.to_vec()),
+        InternalService::Discard => None,
+        InternalService::Daytime => Some(daytime(SystemTime::now())),
+        InternalService::Chargen => {
+            let mut reply = Vec::new();
+            for line in 0..CHARGEN_DATAGRAM_LINES {
+                chargen_line(line, &mut reply);
+            }
+            Some(reply)
+        }
+        InternalService::Time => Some(rfc868_time(SystemTime::now()).to_vec()),
+        // Checked to be stream only
+        InternalService::Tcpmux => None,
+    }
+}
+
+/// Send a short reply to a client and close the connection. The reply fits in the socket buffer
+/// of a new connection, so it is written without waiting.
+pub(crate) fn reply_and_close(mut connection: Connection, reply: &str) {
+    if let Err(err) = connection.stream.write_all(reply.as_bytes()) {
+        debug!("Failed to reply to {}: {}", connection, err);
+    }
+}
+
+/// Append line `n` of the chargen pattern: printable ASCII characters, starting one character
+/// later than the previous line
+fn chargen_line(n: usize, output: &mut Vec<u8>) {
+    const FIRST: u8 = b' ';
+    const CHARS: usize = (b'~' - FIRST + 1) as usize;
+    output.extend((0..CHARGEN_LINE_LEN).map(|idx| FIRST + ((n + idx) % CHARS) as u8));
+    output.extend_from_slice(b"\r\n");
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs())
+}
+
+/// Seconds since 1900 as a 32-bit big-endian number, which wraps in 2036
+fn rfc868_time(time: SystemTime) -> [u8; 4] {
+    ((unix_secs(time) + RFC_868_EPOCH_OFFSET) as u32).to_be_bytes()
+}
+
+/// Local time in the ctime(3) format, e.g. "Sun Oct 18 04:07:42 2026"
+fn daytime(time: SystemTime) -> Vec<u8> {
+    const DAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let secs = unix_secs(time) as libc::time_t;
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    if unsafe { libc::localtime_r(&secs, &mut tm) }.is_null() {
+        return format!("{}\r\n", secs).into_bytes();
+    }
+    format!(
+        "{} {} {:2} {:02}:{:02}:{:02} {}\r\n",
+        DAYS[tm.tm_wday as usize % DAYS.len()],
+        MONTHS[tm.tm_mon as usize % MONTHS.len()],
+        tm.tm_mday,
+        tm.tm_hour,
+        tm.tm_min,
+        tm.tm_sec,
+        tm.tm_year + 1900
+    )
+    .into_bytes()
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn chargen() {
+        let mut output = Vec::new();
+        chargen_line(0, &mut output);
+        chargen_line(1, &mut output);
+        chargen_line(94, &mut output);
+        let lines: Vec<&[u8]> = output.split(|&byte| byte == b'\n').collect();
+        assert_eq!(
+            lines[0],
+            &b" !\"#$%&'()*+,-./0123456789:;<=>?@ABCDEFGHIJKLMNOPQRSTUVWXYZ[\\]^_`abcdefg\r"[..]
+        );
+        assert_eq!(lines[1][0], b'!');
+        assert_eq!(lines[2][..2], *b"~ ");
+        assert_eq!(output.len(), 3 * 74);
+    }
+
+    #[test]
+    fn time() {
+        let epoch = UNIX_EPOCH + Duration::from_secs(0);
+        assert_eq!(rfc868_time(epoch), 2_208_988_800u32.to_be_bytes());
+        // 2036-02-07T06:28:16Z wraps to 0
+        let wrap = UNIX_EPOCH + Duration::from_secs(2_085_978_496);
+        assert_eq!(rfc868_time(wrap), [0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn datagram() {
+        assert_eq!(
+            datagram_reply(InternalService::Echo, b"hello"),
+            Some(b"hello".to_vec())
+        );
+        assert_eq!(datagram_reply(InternalService::Discard, b"hello"), None);
+        let chargen = datagram_reply(InternalService::Chargen, b"").unwrap();
+        assert!(chargen.len() <= 512);
+        assert!(chargen.ends_with(b"\r\n"));
+        let daytime = datagram_reply(InternalService::Daytime, b"").unwrap();
+        assert!(daytime.ends_with(b"\r\n"));
+    }
+}
@@ -0,0 +1,153 @@
+use std::{
+    io,
+    net::SocketAddr,
+    os::unix::io::{AsRawFd, RawFd},
+};
+
+use mio::{
+    event::Source,
+    net::{TcpListener, UdpSocket, UnixDatagram, UnixListener},
+    Interest, Registry, Token,
+};
+
+use super::{
+    connection::{next_connection_id, Connection, Stream},
+    unix, ProtoBinder,
+};
+use crate::{
+    config::{SocketType, UnixAddr},
+    error::StdIoErrorExt,
+    service::Service,
+};
+
+/// Bound socket of a service, of any socket kind served by the event loop
+pub(crate) enum Listener {
+    Tcp(TcpListener),
+    Udp(UdpSocket),
+    Unix {
+        listener: UnixListener,
+        /// Removes the socket file when the listener is dropped
+        _socket_path: Option<unix::SocketPath>,
+    },
+    UnixDgram {
+        socket: UnixDatagram,
+        /// Removes the socket file when the socket is dropped
+        _socket_path: Option<unix::SocketPath>,
+    },
+}
+
+impl Listener {
+    pub(crate) fn bind(service: &Service) -> crate::Result<Self> {
+        fn unix_addr(service: &Service) -> &UnixAddr {
+            service
+                .listen_path
+                .as_ref()
+                .expect("Unix services are checked to have a path")
+        }
+
+        fn bind_proto<P: ProtoBinder>(addr: SocketAddr) -> crate::Result<P> {
+            P::bind_proto(addr).with_message(format!("failed to bind to {}", addr))
+        }
+
+        let listener = match service.effective_socket_type() {
+            SocketType::Tcp => Self::Tcp(bind_proto(service.socket_addr()?)?),
+            SocketType::Udp => Self::Udp(bind_proto(service.socket_addr()?)?),
+            SocketType::Unix => {
+                let (listener, socket_path) = unix::bind_listener(unix_addr(service))?;
+                Self::Unix {
+                    listener,
+                    _socket_path: socket_path,
+                }
+            }
+            SocketType::UnixDgram => {
+                let (socket, socket_path) = unix::bind_datagram(unix_addr(service))?;
+                Self::UnixDgram {
+                    socket,
+                    _socket_path: socket_path,
+                }
+            }
+        };
+        Ok(listener)
+    }
+
+    /// Accept a connection on a stream listener
+    pub(crate) fn accept(&self) -> io::Result<Connection> {
+        match self {
+            Self::Tcp(listener) => {
+                let (stream, peer_addr) = listener.accept()?;
+                let local_addr = stream.local_addr().ok();
+                Ok(Connection {
+                    id: next_connection_id(),
+                    stream: Stream::Tcp(stream),
+                    peer_addr: Some(peer_addr),
+                    local_addr,
+                })
+            }
+            Self::Unix { listener, .. } => {
+                let (stream, _peer_addr) = listener.accept()?;
+                Ok(Connection {
+                    id: next_connection_id(),
+                    stream: Stream::Unix(stream),
+                    peer_addr: None,
+                    local_addr: None,
+                })
+            }
+            Self::Udp(_) | Self::UnixDgram { .. } => {
+                unreachable!("datagram sockets do not accept connections")
+            }
+        }
+    }
+
+    /// Bound address of inet sockets
+    pub(crate) fn local_addr(&self) -> Option<SocketAddr> {
+        match self {
+            Self::Tcp(listener) => listener.local_addr().ok(),
+            Self::Udp(socket) => socket.local_addr().ok(),
+            Self::Unix { .. } | Self::UnixDgram { .. } => None,
+        }
+    }
+
+    fn source(&mut self) -> &mut dyn Source {
+        match self {
+            Self::Tcp(listener) => listener,
+            Self::Udp(socket) => socket,
+            Self::Unix { listener, .. } => listener,
+            Self::UnixDgram { socket, .. } => socket,
+        }
+    }
+}
+
+impl AsRawFd for Listener {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            Self::Tcp(listener) => listener.as_raw_fd(),
+            Self::Udp(socket) => socket.as_raw_fd(),
+            Self::Unix { listener, .. } => listener.as_raw_fd(),
+            Self::UnixDgram { socket, .. } => socket.as_raw_fd(),
+        }
+    }
+}
+
+impl Source for Listener {
+    fn register(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        self.source().register(registry, token, interests)
+    }
+
+    fn reregister(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        self.source().reregister(registry, token, interests)
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        self.source().deregister(registry)
+    }
+}
@@ -1,130 +1,1304 @@
 use std::{
-    io,
+    collections::HashMap,
+    fmt::Display,
+    io::{self, Write},
     net::SocketAddr,
     os::unix::{io::AsRawFd, process::CommandExt},
-    process::{Child, Command},
-    time::Duration,
+    path::PathBuf,
+    process::{Child, Command, ExitStatus, Stdio},
+    sync::{Mutex, PoisonError},
+    time::{Duration, Instant},
 };
 
-use log::trace;
-use mio::{event::Events, Interest, Poll, Token};
-use nix::unistd::dup2;
+use log::{debug, error, info, trace, warn};
+use mio::{event::Events, Interest, Poll, Registry, Token};
+use nix::{sys::signal::Signal, unistd::dup2};
 
 use crate::{
-    config::{Config, SocketType},
-    error::StdIoErrorExt,
+    config::{parse::parse_config_file, Config, InternalService, ServiceType},
+    error::{error_chain, nix_to_io, StdIoErrorExt},
     service::Service,
 };
 
+mod access;
+mod access_times;
+mod connection;
+mod directories;
+mod environment;
+mod internal;
+mod listener;
+mod privileges;
+mod proxy_protocol;
+mod rate_limit;
+mod redirect;
+mod rlimits;
+mod scheduling;
 mod service_state;
+mod signals;
+mod stderr;
 mod tcp;
+mod tcp_wrappers;
 mod udp;
+mod unix;
 
-use service_state::ServiceState;
+use connection::{next_connection_id, Connection, Stream};
+use directories::Directories;
+use environment::{ConnectionEnv, Environment};
+use internal::{InternalConnection, TcpmuxRequest};
+use listener::Listener;
+use privileges::Credentials;
+use proxy_protocol::ProxyConnection;
+use redirect::{RedirectConnection, Target};
+use rlimits::ResourceLimits;
+use scheduling::Scheduling;
+use service_state::{Handoff, ServiceState};
+use signals::SignalSource;
+use stderr::{StderrLog, StderrLogs};
 
 const EVENTS_CAPACITY: usize = 1024;
-const MAX_WAIT: Duration = Duration::from_millis(100);
+
+/// Largest UDP payload
+const MAX_DATAGRAM_LEN: usize = 65536;
+
+/// INTERNAL datagram services do not reply to privileged ports, where other internal services
+/// may listen
+const MIN_REPLY_PORT: u16 = 1024;
+
+/// Shell that runs tcp wrappers commands
+const SHELL: &str = "/bin/sh";
+
+/// Reply of the tcpmux service when it does not start the requested service
+const TCPMUX_UNAVAILABLE: &str = "-Service not available\r\n";
+
+/// Tokens below this are service listeners, whose token is the index of their service state
+const FIRST_CONNECTION_TOKEN: usize = usize::MAX / 2;
+
+/// Token of the signalfd. Internal, redirect and proxy connections and stderr pipes use the tokens
+/// from `FIRST_CONNECTION_TOKEN` up to this one, allocated by `ConnectionToken`.
+const SIGNAL_TOKEN: Token = Token(usize::MAX);
+
+/// Tokens returned by dropped `ConnectionToken`s, and the lowest token never allocated
+#[derive(Debug)]
+struct TokenPool {
+    free: Vec<usize>,
+    next: usize,
+}
+
+impl TokenPool {
+    const fn new() -> Self {
+        Self {
+            free: Vec::new(),
+            next: FIRST_CONNECTION_TOKEN,
+        }
+    }
+
+    fn allocate(&mut self) -> Token {
+        let token = match self.free.pop() {
+            Some(token) => token,
+            None => {
+                assert!(self.next < SIGNAL_TOKEN.0, "out of connection tokens");
+                self.next += 1;
+                self.next - 1
+            }
+        };
+        Token(token)
+    }
+
+    fn release(&mut self, token: Token) {
+        self.free.push(token.0);
+    }
+}
+
+static TOKEN_POOL: Mutex<TokenPool> = Mutex::new(TokenPool::new());
+
+/// Token of a connection or stderr pipe, returned to the pool when dropped so it can be reused
+#[derive(Debug)]
+pub(crate) struct ConnectionToken(Token);
+
+impl ConnectionToken {
+    pub(crate) fn new() -> Self {
+        let mut pool = TOKEN_POOL.lock().unwrap_or_else(PoisonError::into_inner);
+        Self(pool.allocate())
+    }
+
+    pub(crate) fn token(&self) -> Token {
+        self.0
+    }
+}
+
+impl Drop for ConnectionToken {
+    fn drop(&mut self) {
+        let mut pool = TOKEN_POOL.lock().unwrap_or_else(PoisonError::into_inner);
+        pool.release(self.0);
+    }
+}
+
+/// Exit status when children had to be killed on shutdown
+const EXIT_CHILDREN_KILLED: i32 = 2;
+
+/// Options that control the event loop
+#[derive(Debug, Clone)]
+pub struct ServeOptions {
+    /// Config file to re-read on SIGHUP
+    pub config_path: Option<PathBuf>,
+
+    /// Signal sent to children on shutdown
+    pub shutdown_signal: Signal,
+
+    /// How long to wait for children to exit on shutdown before they are killed
+    pub shutdown_timeout: Duration,
+}
+
+impl Default for ServeOptions {
+    fn default() -> Self {
+        Self {
+            config_path: None,
+            shutdown_signal: Signal::SIGTERM,
+            shutdown_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// How the server shut down
+#[derive(Debug, Clone)]
+pub struct Shutdown {
+    /// Signal that requested the shutdown
+    pub signal: Signal,
+
+    /// Number of children that did not exit within the shutdown timeout and were killed
+    pub killed_children: usize,
+}
+
+impl Shutdown {
+    /// Exit status of yinetd: 0, or 2 if children had to be killed
+    pub fn exit_code(&self) -> i32 {
+        if self.killed_children > 0 {
+            EXIT_CHILDREN_KILLED
+        } else {
+            0
+        }
+    }
+}
 
 pub(crate) trait ProtoBinder: mio::event::Source + AsRawFd + Sized {
     fn bind_proto(addr: SocketAddr) -> std::io::Result<Self>;
 }
 
-struct ProtoServerState<'a, P: ProtoBinder> {
+/// State of the event loop that serves every configured service
+struct ServerState {
     /// Map token index to service
-    service_states: Vec<ServiceState<'a, P>>,
+    ///
+    /// Services removed by a reload stay here without a listener until their last child or
+    /// connection ends. They are kept after the configured services, so dropping them does not
+    /// change the token of any listener.
+    service_states: Vec<ServiceState>,
+    signals: SignalSource,
+    stderr_logs: StderrLogs,
+    /// Set once a termination signal was received
+    shutdown_signal: Option<Signal>,
+    /// Set when SIGHUP was received
+    reload_requested: bool,
+    options: ServeOptions,
     poll: Poll,
     events: Events,
 }
 
-pub(crate) fn try_reap_children<P: ProtoBinder>(service_states: &mut Vec<ServiceState<'_, P>>) {
-    for service_state in service_states.iter_mut() {
-        service_state.try_reap_children();
+impl ServerState {
+    fn new(config: Config, options: ServeOptions) -> crate::Result<Self> {
+        let poll = Poll::new().with_message("failed to create mio::Poll")?;
+        let events = Events::with_capacity(EVENTS_CAPACITY);
+
+        let mut signals = SignalSource::new()?;
+        poll.registry()
+            .register(&mut signals, SIGNAL_TOKEN, Interest::READABLE)
+            .with_message("failed to register signalfd with mio")?;
+
+        let mut service_states = Vec::new();
+
+        for service in config.into_services() {
+            let (listener, redirect_addrs) = bind(&service)?;
+            // Use index in service state as the token
+            let token = Token(service_states.len());
+
+            let mut service_state = ServiceState::new(service, listener, redirect_addrs);
+            service_state.update_registration(poll.registry(), token)?;
+            service_states.push(service_state);
+        }
+
+        Ok(Self {
+            service_states,
+            signals,
+            stderr_logs: StderrLogs::default(),
+            shutdown_signal: None,
+            reload_requested: false,
+            options,
+            poll,
+            events,
+        })
+    }
+
+    /// Reap all exited children
+    fn reap_children(&mut self) -> crate::Result<()> {
+        while let Some((pid, status)) = signals::try_wait_any() {
+            self.child_exited(pid, status)?;
+        }
+        Ok(())
+    }
+
+    /// Free the slot of a reaped child, and resume its service if the child was holding it
+    fn child_exited(&mut self, pid: u32, status: ExitStatus) -> crate::Result<()> {
+        for (idx, service_state) in self.service_states.iter_mut().enumerate() {
+            if service_state.child_exited(pid, status) {
+                return service_state.update_registration(self.poll.registry(), Token(idx));
+            }
+        }
+        debug!("Reaped unknown child {} with status {}", pid, status);
+        Ok(())
+    }
+
+    fn handle_signals(&mut self) -> crate::Result<()> {
+        for signal in self.signals.read_pending()? {
+            trace!("Received signal {}", signal);
+            match signal {
+                Signal::SIGTERM | Signal::SIGINT => match self.shutdown_signal {
+                    None => {
+                        info!("Received {}, shutting down", signal);
+                        self.shutdown_signal = Some(signal);
+                    }
+                    Some(_) => info!("Received {}, already shutting down", signal),
+                },
+                Signal::SIGHUP => self.reload_requested = true,
+                // SIGCHLD coalesces, so children are always reaped below
+                _ => {}
+            }
+        }
+        self.reap_children()
+    }
+
+    /// Re-read the config file and apply the differences to the running services.
+    ///
+    /// Unchanged services keep their sockets and children; changed services are rebound; removed
+    /// services are closed. If the config fails to parse, nothing changes.
+    fn reload(&mut self) -> crate::Result<()> {
+        let config_path = match &self.options.config_path {
+            Some(config_path) => config_path,
+            None => {
+                warn!("No config file to reload");
+                return Ok(());
+            }
+        };
+        info!("Reloading config {:?}", config_path);
+        let config = match parse_config_file(config_path) {
+            Ok(config) => config,
+            Err(err) => {
+                error!(
+                    "Failed to reload config, keeping the running config: {}",
+                    error_chain(&err)
+                );
+                return Ok(());
+            }
+        };
+
+        let registry = self.poll.registry();
+        let mut old_states: HashMap<String, ServiceState> = self
+            .service_states
+            .drain(..)
+            .map(|service_state| (service_state.service.name.clone(), service_state))
+            .collect();
+        let mut service_states = Vec::new();
+
+        for service in config.into_services() {
+            let mut service_state = match old_states.remove(&service.name) {
+                Some(mut service_state)
+                    if service_state.service == service && service_state.is_started() =>
+                {
+                    debug!("Service {:?} is unchanged", service.name);
+                    service_state.reload_files();
+                    service_state
+                }
+                Some(mut service_state) => {
+                    info!("Service {:?} changed, rebinding", service.name);
+                    // Close first, so the new listener can bind to the same address
+                    close_listener_or_log(&mut service_state, registry);
+                    let (listener, redirect_addrs) = bind_or_log(&service);
+                    service_state.replace(service, listener, redirect_addrs);
+                    service_state
+                }
+                None => {
+                    info!("Adding service {:?}", service.name);
+                    let (listener, redirect_addrs) = bind_or_log(&service);
+                    ServiceState::new(service, listener, redirect_addrs)
+                }
+            };
+            service_state.removed = false;
+            service_states.push(service_state);
+        }
+
+        for (name, mut service_state) in old_states {
+            if !service_state.removed {
+                info!("Removing service {:?}", name);
+            }
+            service_state.removed = true;
+            close_listener_or_log(&mut service_state, registry);
+            if service_state.instances_count() > 0 {
+                service_states.push(service_state);
+            }
+        }
+
+        // Indexes changed, so every listener needs its new token
+        for (idx, service_state) in service_states.iter_mut().enumerate() {
+            service_state.set_token(registry, Token(idx))?;
+            service_state.update_registration(registry, Token(idx))?;
+        }
+        self.service_states = service_states;
+        Ok(())
+    }
+
+    /// Drop the removed services whose last child or connection ended
+    fn prune_removed(&mut self) {
+        self.service_states.retain(|service_state| {
+            let done = service_state.removed && service_state.instances_count() == 0;
+            if done {
+                debug!(
+                    "Removed service {:?} has no connections left",
+                    service_state.service.name
+                );
+            }
+            !done
+        });
+    }
+
+    fn poll(&mut self, timeout: Option<Duration>) -> crate::Result<()> {
+        match self.poll.poll(&mut self.events, timeout) {
+            Ok(_) => Ok(()),
+            Err(err) => match err.kind() {
+                io::ErrorKind::Interrupted => {
+                    debug!("mio poll interrupted: {}", err);
+                    Ok(())
+                }
+                _ => Err(err).with_message("mio poll failed"),
+            },
+        }
+    }
+
+    /// Time until the next service deadline, if any
+    fn next_timeout(&self) -> Option<Duration> {
+        let deadline = self
+            .service_states
+            .iter()
+            .filter_map(|service_state| service_state.next_deadline())
+            .min()?;
+        Some(deadline.saturating_duration_since(Instant::now()))
+    }
+
+    /// Update registrations of services whose deadlines passed
+    fn update_registrations(&mut self) -> crate::Result<()> {
+        let registry = self.poll.registry();
+        for (idx, service_state) in self.service_states.iter_mut().enumerate() {
+            service_state.update_registration(registry, Token(idx))?;
+        }
+        Ok(())
+    }
+
+    fn children_count(&self) -> usize {
+        self.service_states
+            .iter()
+            .map(|service_state| service_state.children_count())
+            .sum()
+    }
+
+    /// Stop accepting, then ask children to exit and kill those that do not exit in time
+    fn shutdown(&mut self, signal: Signal) -> crate::Result<Shutdown> {
+        for service_state in self.service_states.iter_mut() {
+            service_state.close_listener(self.poll.registry())?;
+            service_state.close_connections(self.poll.registry());
+        }
+
+        let children_count = self.children_count();
+        if children_count > 0 {
+            info!(
+                "Sending {} to {} children",
+                self.options.shutdown_signal, children_count
+            );
+        }
+        for service_state in self.service_states.iter() {
+            service_state.signal_children(self.options.shutdown_signal);
+        }
+
+        let deadline = Instant::now() + self.options.shutdown_timeout;
+        while self.children_count() > 0 {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            self.poll(Some(deadline - now))?;
+            let registry = self.poll.registry();
+            let mut signaled = false;
+            for event in &self.events {
+                match event.token() {
+                    SIGNAL_TOKEN => signaled = true,
+                    token => {
+                        self.stderr_logs.handle_event(registry, token);
+                    }
+                }
+            }
+            if signaled {
+                self.handle_signals()?;
+            }
+        }
+
+        let killed_children = self
+            .service_states
+            .iter_mut()
+            .map(|service_state| service_state.kill_children())
+            .sum();
+        if killed_children > 0 {
+            warn!(
+                "Killed {} children that did not exit within {:?}",
+                killed_children, self.options.shutdown_timeout
+            );
+        }
+
+        Ok(Shutdown {
+            signal,
+            killed_children,
+        })
+    }
+
+    fn serve_forever(&mut self) -> crate::Result<Shutdown> {
+        loop {
+            if let Some(signal) = self.shutdown_signal {
+                return self.shutdown(signal);
+            }
+            if self.reload_requested {
+                self.reload_requested = false;
+                self.reload()?;
+            }
+
+            let timeout = self.next_timeout();
+            self.poll(timeout)?;
+            if timeout.is_some() {
+                self.update_registrations()?;
+            }
+
+            let registry = self.poll.registry();
+            for event in &self.events {
+                let token = event.token();
+                if token == SIGNAL_TOKEN {
+                    continue;
+                }
+                // Before the readable check: a closed pipe may only be read-closed
+                if self.stderr_logs.handle_event(registry, token) {
+                    continue;
+                }
+                if token.0 >= FIRST_CONNECTION_TOKEN {
+                    handle_connection_event(
+                        &mut self.service_states,
+                        registry,
+                        token,
+                        &mut self.stderr_logs,
+                    )?;
+                    continue;
+                }
+                if !event.is_readable() {
+                    continue;
+                }
+                let service_state = &mut self.service_states[token.0];
+                if let (Some(internal_service), true) = (
+                    service_state.service.internal_service(),
+                    service_state.service.wait_mode(),
+                ) {
+                    serve_internal_datagrams(service_state, internal_service);
+                    service_state.update_registration(registry, token)?;
+                } else if service_state.service.wait_mode() {
+                    spawn_wait_server(service_state, registry, token, &mut self.stderr_logs)?;
+                } else {
+                    accept_connections(service_state, registry, &mut self.stderr_logs)?;
+                    service_state.update_registration(registry, token)?;
+                }
+            }
+
+            // Handle signals after the other events so a "wait" socket is re-registered after
+            // its readable event was consumed
+            self.handle_signals()?;
+            self.prune_removed();
+        }
     }
 }
 
-fn create_server_state<P: ProtoBinder>(config: &Config) -> crate::Result<ProtoServerState<P>> {
-    let poll = Poll::new().with_message("failed to create mio::Poll")?;
-    let events = Events::with_capacity(EVENTS_CAPACITY);
+/// Serve an event of an internal, redirect or proxy connection, and resume accepting if its
+/// service was at its instance limit. A TCPMUX client that named a service is handed to that
+/// service, and a connection whose PROXY header was read is served.
+fn handle_connection_event(
+    service_states: &mut [ServiceState],
+    registry: &Registry,
+    token: Token,
+    stderr_logs: &mut StderrLogs,
+) -> crate::Result<()> {
+    let idx = match service_states
+        .iter()
+        .position(|service_state| service_state.has_connection(token))
+    {
+        Some(idx) => idx,
+        None => return Ok(()),
+    };
+    match service_states[idx].handle_connection_event(registry, token) {
+        Some(Handoff::Tcpmux(request)) => {
+            route_tcpmux(service_states, request, registry, stderr_logs)
+        }
+        Some(Handoff::Proxied(connection)) => {
+            debug!(
+                "Connection for service {:?} is from {}",
+                service_states[idx].service.name, connection
+            );
+            handle_connection(&mut service_states[idx], connection, registry, stderr_logs);
+        }
+        None => {}
+    }
+    service_states[idx].update_registration(registry, Token(idx))
+}
 
-    let mut service_states = Vec::new();
+/// Start the TCPMUX service a client asked for, or list the TCPMUX services if it asked for
+/// HELP. Names are case-insensitive (RFC 1078).
+fn route_tcpmux(
+    service_states: &mut [ServiceState],
+    request: TcpmuxRequest,
+    registry: &Registry,
+    stderr_logs: &mut StderrLogs,
+) {
+    let TcpmuxRequest { name, connection } = request;
+    let mut tcpmux_states = service_states.iter_mut().filter(|service_state| {
+        service_state.service.is_tcpmux() && service_state.is_started() && !service_state.removed
+    });
+    if name.eq_ignore_ascii_case("HELP") {
+        let help: String = tcpmux_states
+            .map(|service_state| format!("{}\r\n", service_state.service.name))
+            .collect();
+        internal::reply_and_close(connection, &help);
+        return;
+    }
+    let service_state = match tcpmux_states
+        .find(|service_state| service_state.service.name.eq_ignore_ascii_case(&name))
+    {
+        Some(service_state) => service_state,
+        None => {
+            warn!(
+                "TCPMUX client {} asked for unknown service {:?}",
+                connection, name
+            );
+            internal::reply_and_close(connection, TCPMUX_UNAVAILABLE);
+            return;
+        }
+    };
+    debug!(
+        "TCPMUX client {} asked for service {:?}",
+        connection, service_state.service.name
+    );
+    if let Some(connection) = handle_connection(service_state, connection, registry, stderr_logs) {
+        internal::reply_and_close(connection, TCPMUX_UNAVAILABLE);
+    }
+}
 
-    for service in config.services() {
-        assert_eq!(service.socket_type, SocketType::Tcp);
+/// Accept pending connections and spawn a server for each, until the listener would block or the
+/// service stops accepting
+fn accept_connections(
+    service_state: &mut ServiceState,
+    registry: &Registry,
+    stderr_logs: &mut StderrLogs,
+) -> crate::Result<()> {
+    while service_state.is_accepting() {
+        let accepted = match &service_state.listener {
+            Some(listener) => listener.accept(),
+            None => break,
+        };
+        let connection = match accepted {
+            Ok(connection) => connection,
+            Err(ref err) if would_block(err) => break,
+            Err(err) => return Err(err.with_message("accept failed")),
+        };
 
-        let addr: SocketAddr = service.socket_addr()?;
-        let mut proto_binder = P::bind_proto(addr).with_message("failed to bind to interface")?;
-        // Use index in service state as the token
-        let token = Token(service_states.len());
+        debug!(
+            "Got connection from {} for service {:?}",
+            connection, service_state.service.name
+        );
+        match service_state.service.proxy_protocol {
+            Some(mode) => {
+                let connection = ProxyConnection::new(connection, mode);
+                if let Err(err) = service_state.add_proxy_connection(registry, connection) {
+                    error!("Failed to handle new connection: {}", err);
+                }
+            }
+            None => {
+                handle_connection(service_state, connection, registry, stderr_logs);
+            }
+        }
+    }
+    Ok(())
+}
 
-        poll.registry()
-            .register(&mut proto_binder, token, Interest::READABLE)
-            .with_message(format!(
-                "failed to register service {:?} with mio",
-                service.name
-            ))?;
+/// Spawn a server for an accepted connection, if the service limits allow it.
+///
+/// Returns the connection if it was not served.
+fn handle_connection(
+    service_state: &mut ServiceState,
+    mut connection: Connection,
+    registry: &Registry,
+    stderr_logs: &mut StderrLogs,
+) -> Option<Connection> {
+    let admission = check_access(
+        service_state,
+        connection.peer_addr,
+        connection.local_addr,
+        &connection,
+        "connection",
+    );
+    if let Admission::Refused = admission {
+        return Some(connection);
+    }
+
+    if service_state.at_instance_limit() {
+        warn!(
+            "Service {:?} reached its instance limit, closing connection from {}",
+            service_state.service.name, connection
+        );
+        return Some(connection);
+    }
+
+    if !service_state.try_acquire_rate() {
+        warn!(
+            "Service {:?} exceeded its connection rate, closing connection from {} and disabling \
+             the service for {:?}",
+            service_state.service.name,
+            connection,
+            service_state
+                .service
+                .cps
+                .map(|cps| cps.disable)
+                .unwrap_or_default()
+        );
+        return Some(connection);
+    }
+
+    let peer_ip = connection.peer_addr.map(|peer_addr| peer_addr.ip());
+    if let Some(peer_ip) = peer_ip {
+        if service_state.at_per_source_limit(peer_ip) {
+            warn!(
+                "Service {:?} reached its per_source limit, closing connection from {}",
+                service_state.service.name, connection
+            );
+            return Some(connection);
+        }
+    }
+
+    if service_state.service.r#type == Some(ServiceType::TcpmuxPlus) {
+        // Small enough for the socket buffer of a new connection
+        if let Err(err) = connection.stream.write_all(b"+Go\r\n") {
+            debug!("Failed to reply to TCPMUX client {}: {}", connection, err);
+            return None;
+        }
+    }
+
+    let spawned = match (admission, service_state.service.internal_service()) {
+        (Admission::Twist(command), _) => {
+            spawn_twist(&connection.stream, command.as_str()).map(|child| (child, None))
+        }
+        (_, Some(internal_service)) => {
+            let connection = InternalConnection::new(internal_service, connection);
+            if let Err(err) = service_state.add_internal_connection(registry, connection) {
+                error!("Failed to handle new connection: {}", err);
+            }
+            return None;
+        }
+        (_, None) if service_state.service.redirect.is_some() => {
+            return redirect_connection(service_state, connection, registry);
+        }
+        (_, None) => handle_new_connection(
+            &connection.stream,
+            &service_state.service,
+            &ConnectionEnv {
+                id: connection.id,
+                remote_addr: connection.peer_addr,
+                local_addr: connection.local_addr,
+            },
+        ),
+    };
+    match spawned {
+        Ok((child, stderr_log)) => {
+            service_state.add_child(child, peer_ip);
+            if let Some(stderr_log) = stderr_log {
+                stderr_logs.add(registry, stderr_log);
+            }
+            None
+        }
+        Err(err) => {
+            error!("Failed to handle new connection: {}", err);
+            Some(connection)
+        }
+    }
+}
+
+/// Forward an accepted connection to the `redirect` target of its service.
+///
+/// Returns the connection if it was not forwarded.
+fn redirect_connection(
+    service_state: &mut ServiceState,
+    connection: Connection,
+    registry: &Registry,
+) -> Option<Connection> {
+    let target = match Target::connect(service_state.redirect_addrs()) {
+        Ok(target) => target,
+        Err(err) => {
+            warn!(
+                "Service {:?} failed to connect to {:?}, closing connection from {}: {}",
+                service_state.service.name,
+                service_state.redirect_addrs(),
+                connection,
+                err
+            );
+            return Some(connection);
+        }
+    };
+    let connection = RedirectConnection::new(connection, target);
+    if let Err(err) = service_state.add_redirect_connection(registry, connection) {
+        error!("Failed to handle new connection: {}", err);
+    }
+    None
+}
+
+/// Outcome of the access checks of a client
+enum Admission {
+    Refused,
+    Allowed,
+    /// Allowed, but served by a tcp wrappers `twist` command
+    Twist(String),
+}
 
-        service_states.push(ServiceState::new(service, proto_binder));
+/// Check a client against the service `access_times`, `only_from`/`no_access` and tcp wrappers
+/// rules. `what` the client sent (a connection or a datagram) is logged if it is refused.
+fn check_access(
+    service_state: &ServiceState,
+    client: Option<SocketAddr>,
+    server: Option<SocketAddr>,
+    peer: &dyn Display,
+    what: &str,
+) -> Admission {
+    if !service_state.in_access_times() {
+        warn!(
+            "Service {:?} is outside its access_times, refused {} from {}",
+            service_state.service.name, what, peer
+        );
+        return Admission::Refused;
     }
 
-    Ok(ProtoServerState {
-        service_states,
-        poll,
-        events,
+    let client = match client {
+        Some(client) => client,
+        None => return Admission::Allowed,
+    };
+    if !service_state.allows_client(client.ip()) {
+        warn!(
+            "Service {:?} refused {} from {}",
+            service_state.service.name, what, peer
+        );
+        return Admission::Refused;
+    }
+
+    let verdict = match service_state.check_tcp_wrappers(client, server) {
+        Some(verdict) => verdict,
+        None => return Admission::Allowed,
+    };
+    for command in verdict.spawn.iter() {
+        spawn_shell_command(command);
+    }
+    match verdict.twist {
+        Some(command) => Admission::Twist(command),
+        None if verdict.allow => Admission::Allowed,
+        None => {
+            warn!(
+                "Service {:?} refused {} from {} by tcp wrappers rules",
+                service_state.service.name, what, peer
+            );
+            Admission::Refused
+        }
+    }
+}
+
+/// Answer the datagrams of an INTERNAL datagram service, until the socket would block or the
+/// service stops accepting
+fn serve_internal_datagrams(service_state: &mut ServiceState, internal_service: InternalService) {
+    let mut buf = [0; MAX_DATAGRAM_LEN];
+    while service_state.is_accepting() {
+        let socket = match &service_state.listener {
+            Some(Listener::Udp(socket)) => socket,
+            _ => break,
+        };
+        let (len, peer_addr) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(ref err) if would_block(err) => break,
+            Err(err) => {
+                warn!(
+                    "Service {:?} failed to receive datagram: {}",
+                    service_state.service.name, err
+                );
+                break;
+            }
+        };
+        let local_addr = socket.local_addr().ok();
+
+        match check_access(
+            service_state,
+            Some(peer_addr),
+            local_addr,
+            &peer_addr,
+            "datagram",
+        ) {
+            Admission::Allowed => {}
+            Admission::Refused => continue,
+            Admission::Twist(_) => {
+                warn!(
+                    "Service {:?} refused datagram from {}: tcp wrappers twist is not supported \
+                     by INTERNAL datagram services",
+                    service_state.service.name, peer_addr
+                );
+                continue;
+            }
+        }
+        if !service_state.try_acquire_rate() {
+            warn!(
+                "Service {:?} exceeded its connection rate, dropping datagram from {} and \
+                 disabling the service for {:?}",
+                service_state.service.name,
+                peer_addr,
+                service_state
+                    .service
+                    .cps
+                    .map(|cps| cps.disable)
+                    .unwrap_or_default()
+            );
+            continue;
+        }
+
+        // A reply to another internal service (e.g. echo to chargen) would loop forever
+        if peer_addr.port() < MIN_REPLY_PORT {
+            debug!(
+                "Service {:?} not replying to privileged port of {}",
+                service_state.service.name, peer_addr
+            );
+            continue;
+        }
+        let reply = match internal::datagram_reply(internal_service, &buf[..len]) {
+            Some(reply) => reply,
+            None => continue,
+        };
+        if let Some(Listener::Udp(socket)) = &service_state.listener {
+            if let Err(err) = socket.send_to(&reply, peer_addr) {
+                debug!(
+                    "Service {:?} failed to reply to {}: {}",
+                    service_state.service.name, peer_addr, err
+                );
+            }
+        }
+    }
+}
+
+/// Close the listener of a reloaded service; a failure is logged so the reload goes on
+fn close_listener_or_log(service_state: &mut ServiceState, registry: &Registry) {
+    if let Err(err) = service_state.close_listener(registry) {
+        error!(
+            "Failed to close listener of service {:?}: {}",
+            service_state.service.name,
+            error_chain(&err)
+        );
+    }
+}
+
+/// Resolve the `redirect` target of a service and bind its listener. TCPMUX services are reached
+/// through the tcpmux service, so they have no listener.
+fn bind(service: &Service) -> crate::Result<(Option<Listener>, Vec<SocketAddr>)> {
+    let redirect_addrs = redirect::resolve(service)?;
+    let listener = match service.is_tcpmux() {
+        true => None,
+        false => Some(Listener::bind(service)?),
+    };
+    Ok((listener, redirect_addrs))
+}
+
+/// Bind a reloaded service; failures are logged so other services still reload
+fn bind_or_log(service: &Service) -> (Option<Listener>, Vec<SocketAddr>) {
+    bind(service).unwrap_or_else(|err| {
+        error!(
+            "Failed to bind service {:?}: {}",
+            service.name,
+            error_chain(&err)
+        );
+        (None, Vec::new())
     })
 }
 
-pub fn serve_forever(config: Config) -> crate::Result<()> {
-    tcp::serve_tcp_forever(config)
-    // todo(tmfink): handle other protocols
+/// Handle a readable socket with inetd "wait" semantics: the bound socket itself is handed to the
+/// server, and is not polled again until that server exits.
+fn spawn_wait_server(
+    service_state: &mut ServiceState,
+    registry: &Registry,
+    token: Token,
+    stderr_logs: &mut StderrLogs,
+) -> crate::Result<()> {
+    let listener = match &service_state.listener {
+        Some(listener) if !service_state.is_waiting() => listener,
+        _ => return Ok(()),
+    };
+
+    debug!(
+        "Spawning wait server for service {:?}",
+        service_state.service.name
+    );
+    let connection_env = ConnectionEnv {
+        id: next_connection_id(),
+        remote_addr: None,
+        local_addr: listener.local_addr(),
+    };
+    match handle_new_connection(listener, &service_state.service, &connection_env) {
+        Ok((child, stderr_log)) => {
+            // The child owns the socket until it exits
+            service_state.add_wait_child(child);
+            service_state.update_registration(registry, token)?;
+            if let Some(stderr_log) = stderr_log {
+                stderr_logs.add(registry, stderr_log);
+            }
+        }
+        Err(err) => {
+            error!("Failed to spawn wait server: {}", err);
+        }
+    }
+    Ok(())
+}
+
+/// Serve `config` until a termination signal is received
+pub fn serve_forever(config: Config, options: ServeOptions) -> crate::Result<Shutdown> {
+    ServerState::new(config, options)?.serve_forever()
 }
 
-fn make_fd_blocking(fd: libc::c_int) {
-    let fd_flag_bits = nix::fcntl::fcntl(fd, nix::fcntl::FcntlArg::F_GETFL).unwrap();
-    let mut fd_flags = nix::fcntl::OFlag::from_bits(fd_flag_bits).unwrap();
+fn set_fd_nonblocking(fd: libc::c_int, nonblocking: bool) -> nix::Result<()> {
+    let fd_flag_bits = nix::fcntl::fcntl(fd, nix::fcntl::FcntlArg::F_GETFL)?;
+    let mut fd_flags = nix::fcntl::OFlag::from_bits_truncate(fd_flag_bits);
     trace!("fd {} old flags: {:?}", fd, fd_flags);
 
-    fd_flags.remove(nix::fcntl::OFlag::O_NONBLOCK);
+    fd_flags.set(nix::fcntl::OFlag::O_NONBLOCK, nonblocking);
     trace!("fd {} new flags: {:?}", fd, fd_flags);
 
-    nix::fcntl::fcntl(fd, nix::fcntl::FcntlArg::F_SETFL(fd_flags)).unwrap();
+    nix::fcntl::fcntl(fd, nix::fcntl::FcntlArg::F_SETFL(fd_flags))?;
+    Ok(())
 }
 
-fn handle_new_connection<C: AsRawFd>(connection: C, service: &Service) -> crate::Result<Child> {
+/// Spawn the service server with `connection` as its stdin/stdout, and its stderr as configured.
+///
+/// The caller keeps ownership of `connection`; it should be dropped (closed) after the spawn unless
+/// the parent still needs it. In `log` stderr mode, the read end of the stderr pipe is returned.
+fn handle_new_connection<C: AsRawFd>(
+    connection: &C,
+    service: &Service,
+    connection_env: &ConnectionEnv,
+) -> crate::Result<(Child, Option<StderrLog>)> {
+    let server = service
+        .server
+        .as_deref()
+        .expect("external services are checked to have a server");
+    let mut cmd = Command::new(server);
+    cmd.args(&service.server_args.0)
+        .env_clear()
+        .envs(Environment::for_service(service, connection_env)?.vars());
+    let (stderr, stderr_log) = stderr::server_stderr(service, connection, connection_env.id)?;
+    if let Some(stderr) = stderr {
+        cmd.stderr(stderr);
+    }
+    let rlimits = ResourceLimits::for_service(service);
+    if !rlimits.is_empty() {
+        // Before dropping privileges, which may prevent raising hard limits
+        unsafe {
+            cmd.pre_exec(move || rlimits.apply());
+        }
+    }
+    if let Some(scheduling) = Scheduling::for_service(service) {
+        // Before dropping privileges, which may prevent raising priorities
+        unsafe {
+            cmd.pre_exec(move || scheduling.apply());
+        }
+    }
+    if let Some(directories) = Directories::for_service(service)? {
+        // After scheduling, which needs /proc; before dropping privileges, which chroot needs
+        unsafe {
+            cmd.pre_exec(move || directories.apply());
+        }
+    }
+    if let Some(credentials) = Credentials::for_service(service)? {
+        // A failed drop fails the spawn, so the server never runs with our privileges
+        unsafe {
+            cmd.pre_exec(move || credentials.apply());
+        }
+    }
+    let child = spawn_on_socket(connection, cmd).with_message(format!(
+        "failed to spawn child process executable {:?}",
+        server
+    ))?;
+    // The write end of the stderr pipe was closed with `cmd`, so the pipe closes with the server
+    Ok((child, stderr_log))
+}
+
+/// Serve `connection` with a tcp wrappers `twist` shell command instead of the server.
+///
+/// The command's stderr also goes to the client.
+fn spawn_twist(connection: &Stream, command: &str) -> crate::Result<Child> {
+    let stderr = stderr::dup_stdio(connection)?;
+    let mut cmd = Command::new(SHELL);
+    cmd.arg("-c")
+        .arg(command)
+        .env_clear()
+        .envs(Environment::default().vars())
+        .stderr(stderr);
+    spawn_on_socket(connection, cmd)
+        .with_message(format!("failed to spawn twist command {:?}", command))
+}
+
+/// Run a tcp wrappers `spawn` shell command in the background.
+///
+/// The child is not tracked; it is reaped like any other child.
+fn spawn_shell_command(command: &str) {
+    let result = Command::new(SHELL)
+        .arg("-c")
+        .arg(command)
+        .env_clear()
+        .envs(Environment::default().vars())
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn();
+    if let Err(err) = result {
+        error!("Failed to spawn command {:?}: {}", command, err);
+    }
+}
+
+/// Spawn `cmd` with `connection` as its stdin/stdout
+fn spawn_on_socket<C: AsRawFd>(connection: &C, mut cmd: Command) -> io::Result<Child> {
     let sock_fd = connection.as_raw_fd();
 
-    let mut cmd = Command::new(&service.server);
-    cmd.args(&service.server_args.0);
     unsafe {
         cmd.pre_exec(move || {
             trace!("in child");
 
             // dup stdin/out/err to socket
             for &fd in [libc::STDIN_FILENO, libc::STDOUT_FILENO].iter() {
-                if let Err(err) = dup2(sock_fd, fd) {
-                    panic!("Failed to dup2() fd {} as socket fd: {}", fd, err);
-                }
+                dup2(sock_fd, fd).map_err(nix_to_io)?;
                 trace!("dup'd child fd {} to socket fd", fd);
 
                 // after duping the socket, the fd will inherit non-blocking from the listener socket
-                make_fd_blocking(fd);
+                set_fd_nonblocking(fd, false).map_err(nix_to_io)?;
             }
 
             Ok(())
         });
     }
-    let child = match cmd.spawn() {
-        Ok(child) => child,
-        Err(err) => {
-            return Err(err.with_message(format!(
-                "failed to spawn child process executable {:?}",
-                service.server
-            )))
-        }
-    };
-
-    Ok(child)
+    cmd.spawn()
 }
 
 fn would_block(err: &io::Error) -> bool {
     err.kind() == io::ErrorKind::WouldBlock
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::parse::parse_config_str;
+    use std::os::unix::process::ExitStatusExt;
+
+    /// Held by tests that start children: reaping any child (`waitpid(-1)`) could otherwise
+    /// reap the children of another test
+    static CHILDREN: Mutex<()> = Mutex::new(());
+
+    fn server_state(config: &str) -> ServerState {
+        let config = parse_config_str(config).unwrap();
+        ServerState::new(config, ServeOptions::default()).unwrap()
+    }
+
+    /// Wait for the child `pid` itself, without reaping the children of other tests
+    fn wait_exited(pid: u32) -> ExitStatus {
+        let mut status = 0;
+        let waited = unsafe { libc::waitpid(pid as libc::pid_t, &mut status, 0) };
+        assert_eq!(waited, pid as libc::pid_t);
+        ExitStatus::from_raw(status)
+    }
+
+    #[test]
+    fn reaped_child_frees_its_slot() {
+        let _children = CHILDREN.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut state = server_state(
+            "
+service limited {
+    server = /bin/true
+    port = 0
+    listen_address = 127.0.0.1
+    instances = 1
+}
+service waiting {
+    server = /bin/true
+    port = 0
+    listen_address = 127.0.0.1
+    socket_type = udp
+    wait = yes
+}
+",
+        );
+
+        let child = Command::new("true").spawn().unwrap();
+        let pid = child.id();
+        state.service_states[0].add_child(child, None);
+        assert!(state.service_states[0].at_instance_limit());
+        let wait_child = Command::new("true").spawn().unwrap();
+        let wait_pid = wait_child.id();
+        state.service_states[1].add_wait_child(wait_child);
+
+        let status = wait_exited(pid);
+        state.child_exited(pid, status).unwrap();
+        state.child_exited(wait_pid, wait_exited(wait_pid)).unwrap();
+        assert_eq!(state.children_count(), 0);
+        assert!(!state.service_states[0].at_instance_limit());
+        assert!(!state.service_states[1].is_waiting());
+
+        // Children that were already reaped are ignored
+        state.child_exited(pid, status).unwrap();
+    }
+
+    #[test]
+    fn reload_diff() {
+        let dir = std::env::temp_dir().join(format!("yinetd-reload-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config_path = dir.join("yinetd.conf");
+        let service = |name: &str, args: &str| {
+            format!(
+                "service {} {{\n    server = /bin/echo\n    server_args = {}\n    port = 0\n    \
+                 listen_address = 127.0.0.1\n}}\n",
+                name, args
+            )
+        };
+        let write_config = |services: &[String]| std::fs::write(&config_path, services.concat());
+
+        write_config(&[
+            service("unchanged", "a"),
+            service("changed", "a"),
+            service("removed", "a"),
+            service("idle", "a"),
+        ])
+        .unwrap();
+        let options = ServeOptions {
+            config_path: Some(config_path.clone()),
+            ..ServeOptions::default()
+        };
+        let _children = CHILDREN.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut state =
+            ServerState::new(parse_config_file(&config_path).unwrap(), options).unwrap();
+        let unchanged_fd = state.service_states[0]
+            .listener
+            .as_ref()
+            .map(AsRawFd::as_raw_fd);
+        assert!(unchanged_fd.is_some());
+        for service_state in state.service_states[..3].iter_mut() {
+            let child = Command::new("sleep").arg("60").spawn().unwrap();
+            service_state.add_child(child, None);
+        }
+
+        write_config(&[
+            service("added", "a"),
+            service("changed", "b"),
+            service("unchanged", "a"),
+        ])
+        .unwrap();
+        state.reload().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let names: Vec<&str> = state
+            .service_states
+            .iter()
+            .map(|service_state| service_state.service.name.as_str())
+            .collect();
+        // The removed service with a child is kept after the configured ones
+        assert_eq!(names, ["added", "changed", "unchanged", "removed"]);
+
+        let added = &state.service_states[0];
+        assert!(added.listener.is_some());
+        assert_eq!(added.children_count(), 0);
+
+        let changed = &state.service_states[1];
+        assert_eq!(changed.service.server_args.0, ["b"]);
+        assert!(changed.listener.is_some());
+        assert_eq!(changed.children_count(), 1);
+
+        // The listener of an unchanged service is kept open
+        let unchanged = &state.service_states[2];
+        assert_eq!(
+            unchanged.listener.as_ref().map(AsRawFd::as_raw_fd),
+            unchanged_fd
+        );
+        assert_eq!(unchanged.children_count(), 1);
+
+        let removed = &mut state.service_states[3];
+        assert!(removed.removed && removed.listener.is_none());
+        assert_eq!(removed.kill_children(), 1);
+        state.prune_removed();
+        assert_eq!(state.service_states.len(), 3);
+
+        for service_state in state.service_states.iter_mut() {
+            service_state.kill_children();
+        }
+    }
+
+    #[test]
+    fn shutdown_kills_children_after_timeout() {
+        const CONFIG: &str = "
+service stubborn {
+    server = /bin/true
+    port = 0
+    listen_address = 127.0.0.1
+}
+";
+        let _children = CHILDREN.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut state = server_state(CONFIG);
+        let timeout = Duration::from_millis(100);
+        state.options.shutdown_timeout = timeout;
+        let mut child = Command::new("/bin/sh")
+            .arg("-c")
+            .arg("trap '' TERM; echo ready; exec sleep 60")
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        // Only send SIGTERM once the child ignores it
+        let mut ready = [0; 6];
+        io::Read::read_exact(child.stdout.as_mut().unwrap(), &mut ready).unwrap();
+        state.service_states[0].add_child(child, None);
+
+        let start = Instant::now();
+        let shutdown = state.shutdown(Signal::SIGTERM).unwrap();
+        assert!(start.elapsed() >= timeout);
+        assert_eq!(shutdown.killed_children, 1);
+        assert_eq!(shutdown.exit_code(), 2);
+        assert_eq!(state.children_count(), 0);
+        assert!(state.service_states[0].listener.is_none());
+
+        let shutdown = server_state(CONFIG).shutdown(Signal::SIGTERM).unwrap();
+        assert_eq!(shutdown.killed_children, 0);
+        assert_eq!(shutdown.exit_code(), 0);
+    }
+
+    #[test]
+    fn connection_tokens() {
+        let mut pool = TokenPool::new();
+        let first = pool.allocate();
+        let second = pool.allocate();
+        for token in [first, second].iter() {
+            assert!(token.0 >= FIRST_CONNECTION_TOKEN && *token < SIGNAL_TOKEN);
+        }
+        assert_ne!(first, second);
+
+        // Released tokens are reused
+        pool.release(first);
+        assert_eq!(pool.allocate(), first);
+        assert_ne!(pool.allocate(), second);
+    }
+}
@@ -0,0 +1,194 @@
+use std::{ffi::CString, io};
+
+use nix::unistd::{getgrouplist, setgid, setgroups, setuid, Gid, Group, Uid, User};
+
+use crate::{
+    config::Account,
+    error::{nix_to_io, StdIoErrorExt},
+    service::Service,
+    Error,
+};
+
+/// Identity a server runs as, from the service `user` and `group`.
+///
+/// Names are resolved in the parent, so the child only makes system calls.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Credentials {
+    /// `None` if only the group changes
+    uid: Option<Uid>,
+    gid: Gid,
+    /// Supplementary groups
+    groups: Vec<Gid>,
+}
+
+impl Credentials {
+    /// Resolve the credentials of `service`; `None` if the server keeps our identity
+    pub(crate) fn for_service(service: &Service) -> crate::Result<Option<Self>> {
+        let user = match service.run_as_user() {
+            Some(account) => Some(resolve_user(&account, service)?),
+            None => None,
+        };
+        let group = match &service.group {
+            Some(account) => Some(resolve_group(account, service)?),
+            None => None,
+        };
+
+        let (uid, user) = match user {
+            Some(user) => user,
+            None => {
+                return Ok(group.map(|gid| Self {
+                    uid: None,
+                    gid,
+                    groups: vec![gid],
+                }))
+            }
+        };
+        let (gid, groups) = match (user, group) {
+            (Some(user), group) => {
+                let gid = group.unwrap_or(user.gid);
+                let name = CString::new(user.name.as_str())
+                    .expect("user names from the passwd database have no NUL");
+                let groups = getgrouplist(&name, gid).with_message(format!(
+                    "failed to get supplementary groups of user {:?}",
+                    user.name
+                ))?;
+                (gid, groups)
+            }
+            (None, Some(gid)) => (gid, vec![gid]),
+            (None, None) => {
+                return Err(Error::NoPrimaryGroup {
+                    user: uid.as_raw(),
+                    service: service.name.clone(),
+                })
+            }
+        };
+        Ok(Some(Self {
+            uid: Some(uid),
+            gid,
+            groups,
+        }))
+    }
+
+    /// Switch to these credentials. Called in the child before exec; groups must be set while
+    /// still privileged.
+    pub(crate) fn apply(&self) -> io::Result<()> {
+        setgroups(&self.groups).map_err(nix_to_io)?;
+        setgid(self.gid).map_err(nix_to_io)?;
+        if let Some(uid) = self.uid {
+            setuid(uid).map_err(nix_to_io)?;
+        }
+        Ok(())
+    }
+}
+
+/// Resolve a user; numeric IDs may lack a passwd entry
+fn resolve_user(account: &Account, service: &Service) -> crate::Result<(Uid, Option<User>)> {
+    match account {
+        Account::Id(uid) => {
+            let uid = Uid::from_raw(*uid);
+            let user =
+                User::from_uid(uid).with_message(format!("failed to look up user {}", uid))?;
+            Ok((uid, user))
+        }
+        Account::Name(name) => {
+            let user = User::from_name(name)
+                .with_message(format!("failed to look up user {:?}", name))?
+                .ok_or_else(|| Error::UnknownAccount {
+                    kind: "user",
+                    account: account.to_string(),
+                    service: service.name.clone(),
+                })?;
+            Ok((user.uid, Some(user)))
+        }
+    }
+}
+
+fn resolve_group(account: &Account, service: &Service) -> crate::Result<Gid> {
+    match account {
+        Account::Id(gid) => Ok(Gid::from_raw(*gid)),
+        Account::Name(name) => {
+            let group = Group::from_name(name)
+                .with_message(format!("failed to look up group {:?}", name))?
+                .ok_or_else(|| Error::UnknownAccount {
+                    kind: "group",
+                    account: account.to_string(),
+                    service: service.name.clone(),
+                })?;
+            Ok(group.gid)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::parse::{parse_config_str, parse_test_service};
+
+    /// UID and GID without passwd or group entries
+    const NO_ACCOUNT: u32 = 4_000_000;
+
+    fn credentials(options: &str) -> crate::Result<Option<Credentials>> {
+        Credentials::for_service(&parse_test_service(options))
+    }
+
+    #[test]
+    fn for_service() {
+        assert_eq!(credentials("").unwrap(), None);
+
+        // A user name resolves like its UID, with the groups of the user
+        let root = credentials("user = root").unwrap().unwrap();
+        assert_eq!(root, credentials("uid = 0").unwrap().unwrap());
+        assert_eq!(root.uid, Some(Uid::from_raw(0)));
+        assert_eq!(root.gid, Gid::from_raw(0));
+        assert!(root.groups.contains(&Gid::from_raw(0)));
+
+        // `group` replaces the primary group of the user
+        let with_group = credentials("user = root\n group = 1").unwrap().unwrap();
+        assert_eq!(with_group.gid, Gid::from_raw(1));
+        assert!(with_group.groups.contains(&Gid::from_raw(1)));
+
+        assert_eq!(
+            credentials("group = 1").unwrap(),
+            Some(Credentials {
+                uid: None,
+                gid: Gid::from_raw(1),
+                groups: vec![Gid::from_raw(1)],
+            })
+        );
+
+        // A UID without passwd entry has no primary or supplementary groups
+        let no_user = format!("uid = {}", NO_ACCOUNT);
+        match credentials(&no_user) {
+            Err(Error::NoPrimaryGroup { user, .. }) => assert_eq!(user, NO_ACCOUNT),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert_eq!(
+            credentials(&format!("{}\n group = {}", no_user, NO_ACCOUNT)).unwrap(),
+            Some(Credentials {
+                uid: Some(Uid::from_raw(NO_ACCOUNT)),
+                gid: Gid::from_raw(NO_ACCOUNT),
+                groups: vec![Gid::from_raw(NO_ACCOUNT)],
+            })
+        );
+
+        match credentials("user = yinetd-no-such-user") {
+            Err(Error::UnknownAccount { kind, .. }) => assert_eq!(kind, "user"),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        match credentials("user = root\n group = yinetd-no-such-group") {
+            Err(Error::UnknownAccount { kind, .. }) => assert_eq!(kind, "group"),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        // `user` and `uid` may not both be set, so they cannot disagree
+        let config = "
+service s
+{
+    server = s
+    port = 1
+    user = root
+    uid = 1
+}
+";
+        assert!(parse_config_str(config).is_err());
+    }
+}
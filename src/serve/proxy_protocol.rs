@@ -0,0 +1,388 @@
+use std::{
+    convert::TryInto,
+    io::{self, Read},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    str,
+    time::{Duration, Instant},
+};
+
+use mio::{Interest, Registry, Token};
+
+use super::{connection::Connection, ConnectionToken};
+use crate::config::ProxyProtocol;
+
+/// How long a load balancer has to send the PROXY header
+const HEADER_TIMEOUT: Duration = Duration::from_secs(10);
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+/// Longest v1 header, including the CRLF
+const V1_MAX_LEN: usize = 107;
+
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+/// Length of the v2 header before the addresses
+const V2_FIXED_LEN: usize = 16;
+const V2_VERSION: u8 = 2;
+const V2_CMD_LOCAL: u8 = 0;
+const V2_CMD_PROXY: u8 = 1;
+const V2_TCP4: u8 = 0x11;
+const V2_TCP6: u8 = 0x21;
+
+/// Longest header accepted, including v2 TLVs
+const MAX_HEADER_LEN: usize = 4096;
+
+/// PROXY header at the start of a connection
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Header {
+    /// Length of the header; 0 if there is none
+    pub(crate) len: usize,
+    /// Client and server addresses; `None` for connections of the load balancer itself (e.g.
+    /// health checks), which keep their own addresses
+    pub(crate) addrs: Option<(SocketAddr, SocketAddr)>,
+}
+
+/// Parse the PROXY header at the start of `buf`.
+///
+/// Returns `None` if more data is needed.
+pub(crate) fn parse_header(
+    buf: &[u8],
+    mode: ProxyProtocol,
+) -> Result<Option<Header>, &'static str> {
+    let mut incomplete = false;
+    if mode != ProxyProtocol::V2 {
+        match starts_with(buf, V1_PREFIX) {
+            Some(true) => return parse_v1(buf),
+            Some(false) => {}
+            None => incomplete = true,
+        }
+    }
+    if mode != ProxyProtocol::V1 {
+        match starts_with(buf, V2_SIGNATURE) {
+            Some(true) => return parse_v2(buf),
+            Some(false) => {}
+            None => incomplete = true,
+        }
+    }
+    if incomplete {
+        return Ok(None);
+    }
+    match mode {
+        ProxyProtocol::V1 => Err("missing PROXY protocol v1 header"),
+        ProxyProtocol::V2 => Err("missing PROXY protocol v2 header"),
+        ProxyProtocol::Optional => Ok(Some(Header {
+            len: 0,
+            addrs: None,
+        })),
+    }
+}
+
+/// Whether `buf` starts with `prefix`; `None` if `buf` is too short to tell
+fn starts_with(buf: &[u8], prefix: &[u8]) -> Option<bool> {
+    if buf.len() < prefix.len() {
+        return match prefix.starts_with(buf) {
+            true => None,
+            false => Some(false),
+        };
+    }
+    Some(buf.starts_with(prefix))
+}
+
+/// Parse "PROXY TCP4|TCP6 <src ip> <dst ip> <src port> <dst port>\r\n" or "PROXY UNKNOWN ...\r\n"
+fn parse_v1(buf: &[u8]) -> Result<Option<Header>, &'static str> {
+    const ERR: &str = "invalid PROXY protocol v1 header";
+    let end = match buf
+        .windows(2)
+        .take(V1_MAX_LEN - 1)
+        .position(|bytes| bytes == b"\r\n")
+    {
+        Some(end) => end,
+        None if buf.len() >= V1_MAX_LEN => return Err("PROXY protocol v1 header is too long"),
+        None => return Ok(None),
+    };
+    let line = str::from_utf8(&buf[V1_PREFIX.len()..end]).map_err(|_| ERR)?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    let addrs = match fields.as_slice() {
+        ["UNKNOWN", ..] => None,
+        [proto @ "TCP4", src, dst, src_port, dst_port]
+        | [proto @ "TCP6", src, dst, src_port, dst_port] => {
+            let src: IpAddr = src.parse().map_err(|_| ERR)?;
+            let dst: IpAddr = dst.parse().map_err(|_| ERR)?;
+            let ipv4 = *proto == "TCP4";
+            if src.is_ipv4() != ipv4 || dst.is_ipv4() != ipv4 {
+                return Err(ERR);
+            }
+            let src_port = src_port.parse().map_err(|_| ERR)?;
+            let dst_port = dst_port.parse().map_err(|_| ERR)?;
+            Some((
+                SocketAddr::new(src, src_port),
+                SocketAddr::new(dst, dst_port),
+            ))
+        }
+        _ => return Err(ERR),
+    };
+    Ok(Some(Header {
+        len: end + 2,
+        addrs,
+    }))
+}
+
+/// Parse the binary header: signature, version and command, family and protocol, length, then
+/// the addresses and TLVs
+fn parse_v2(buf: &[u8]) -> Result<Option<Header>, &'static str> {
+    if buf.len() < V2_FIXED_LEN {
+        return Ok(None);
+    }
+    let (version, command) = (buf[12] >> 4, buf[12] & 0xf);
+    if version != V2_VERSION {
+        return Err("unsupported PROXY protocol v2 version");
+    }
+    let len = V2_FIXED_LEN + u16::from_be_bytes([buf[14], buf[15]]) as usize;
+    if len > MAX_HEADER_LEN {
+        return Err("PROXY protocol v2 header is too long");
+    }
+    if buf.len() < len {
+        return Ok(None);
+    }
+    let addrs = &buf[V2_FIXED_LEN..len];
+    let addrs = match (command, buf[13]) {
+        (V2_CMD_LOCAL, _) => None,
+        (V2_CMD_PROXY, V2_TCP4) if addrs.len() >= 12 => {
+            let src: [u8; 4] = addrs[0..4].try_into().expect("slice has 4 bytes");
+            let dst: [u8; 4] = addrs[4..8].try_into().expect("slice has 4 bytes");
+            Some((
+                SocketAddr::new(Ipv4Addr::from(src).into(), be_u16(&addrs[8..10])),
+                SocketAddr::new(Ipv4Addr::from(dst).into(), be_u16(&addrs[10..12])),
+            ))
+        }
+        (V2_CMD_PROXY, V2_TCP6) if addrs.len() >= 36 => {
+            let src: [u8; 16] = addrs[0..16].try_into().expect("slice has 16 bytes");
+            let dst: [u8; 16] = addrs[16..32].try_into().expect("slice has 16 bytes");
+            Some((
+                SocketAddr::new(Ipv6Addr::from(src).into(), be_u16(&addrs[32..34])),
+                SocketAddr::new(Ipv6Addr::from(dst).into(), be_u16(&addrs[34..36])),
+            ))
+        }
+        (V2_CMD_PROXY, V2_TCP4) | (V2_CMD_PROXY, V2_TCP6) => {
+            return Err("truncated PROXY protocol v2 addresses")
+        }
+        // Unspecified, UDP or Unix: the connection keeps its own addresses
+        (V2_CMD_PROXY, _) => None,
+        _ => return Err("invalid PROXY protocol v2 command"),
+    };
+    Ok(Some(Header { len, addrs }))
+}
+
+fn be_u16(bytes: &[u8]) -> u16 {
+    u16::from_be_bytes([bytes[0], bytes[1]])
+}
+
+/// Accepted connection waiting for its PROXY header, polled by the event loop
+pub(crate) struct ProxyConnection {
+    connection: Connection,
+    mode: ProxyProtocol,
+    token: ConnectionToken,
+    deadline: Instant,
+}
+
+impl ProxyConnection {
+    pub(crate) fn new(connection: Connection, mode: ProxyProtocol) -> Self {
+        Self {
+            token: ConnectionToken::new(),
+            connection,
+            mode,
+            deadline: Instant::now() + HEADER_TIMEOUT,
+        }
+    }
+
+    pub(crate) fn token(&self) -> Token {
+        self.token.token()
+    }
+
+    /// Load balancer of the connection, until the header is read
+    pub(crate) fn peer(&self) -> &Connection {
+        &self.connection
+    }
+
+    pub(crate) fn deadline(&self) -> Instant {
+        self.deadline
+    }
+
+    pub(crate) fn into_connection(self) -> Connection {
+        self.connection
+    }
+
+    pub(crate) fn register(&mut self, registry: &Registry) -> io::Result<()> {
+        registry.register(
+            &mut self.connection.stream,
+            self.token.token(),
+            Interest::READABLE,
+        )
+    }
+
+    pub(crate) fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        registry.deregister(&mut self.connection.stream)
+    }
+
+    /// Read the header, without reading past it: the rest is for the server. The connection then
+    /// has the addresses of the header.
+    ///
+    /// Returns whether the header was read. A malformed header is an `InvalidData` error.
+    pub(crate) fn read_header(&mut self) -> io::Result<bool> {
+        let mut buf = [0; MAX_HEADER_LEN];
+        let len = match self.connection.stream.peek(&mut buf) {
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(len) => len,
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+            Err(err) => return Err(err),
+        };
+        let header = match parse_header(&buf[..len], self.mode)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?
+        {
+            Some(header) => header,
+            None => return Ok(false),
+        };
+        // Already received, so this does not block
+        self.connection.stream.read_exact(&mut buf[..header.len])?;
+        if let Some((client, server)) = header.addrs {
+            self.connection.peer_addr = Some(client);
+            self.connection.local_addr = Some(server);
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn addrs(client: &str, server: &str) -> Option<(SocketAddr, SocketAddr)> {
+        Some((client.parse().unwrap(), server.parse().unwrap()))
+    }
+
+    #[test]
+    fn v1() {
+        let buf = b"PROXY TCP4 192.0.2.1 198.51.100.1 40000 22\r\nSSH-2.0";
+        assert_eq!(
+            parse_header(buf, ProxyProtocol::V1),
+            Ok(Some(Header {
+                len: 44,
+                addrs: addrs("192.0.2.1:40000", "198.51.100.1:22"),
+            }))
+        );
+        assert_eq!(
+            parse_header(
+                b"PROXY TCP6 2001:db8::1 2001:db8::2 1 2\r\n",
+                ProxyProtocol::V1
+            )
+            .unwrap()
+            .unwrap()
+            .addrs,
+            addrs("[2001:db8::1]:1", "[2001:db8::2]:2")
+        );
+        assert_eq!(
+            parse_header(b"PROXY UNKNOWN\r\n", ProxyProtocol::Optional),
+            Ok(Some(Header {
+                len: 15,
+                addrs: None
+            }))
+        );
+
+        // Incomplete
+        assert_eq!(parse_header(b"PRO", ProxyProtocol::V1), Ok(None));
+        assert_eq!(
+            parse_header(b"PROXY TCP4 192.0.2.1", ProxyProtocol::V1),
+            Ok(None)
+        );
+
+        let mut too_long = b"PROXY UNKNOWN ".to_vec();
+        too_long.resize(V1_MAX_LEN, b'x');
+        for buf in [
+            &b"PROXY TCP4 2001:db8::1 192.0.2.1 1 2\r\n"[..],
+            b"PROXY TCP4 192.0.2.1 192.0.2.2 1\r\n",
+            b"PROXY TCP4 192.0.2.1 192.0.2.2 1 65536\r\n",
+            b"PROXY UDP4 192.0.2.1 192.0.2.2 1 2\r\n",
+            b"SSH-2.0-OpenSSH\r\n",
+            &too_long,
+        ]
+        .iter()
+        {
+            assert!(parse_header(buf, ProxyProtocol::V1).is_err());
+        }
+    }
+
+    fn v2_header(command: u8, family: u8, addrs: &[u8]) -> Vec<u8> {
+        let mut buf = V2_SIGNATURE.to_vec();
+        buf.push(0x20 | command);
+        buf.push(family);
+        buf.extend_from_slice(&(addrs.len() as u16).to_be_bytes());
+        buf.extend_from_slice(addrs);
+        buf
+    }
+
+    #[test]
+    fn v2() {
+        let mut buf = v2_header(
+            V2_CMD_PROXY,
+            V2_TCP4,
+            &[192, 0, 2, 1, 198, 51, 100, 1, 0x9c, 0x40, 0, 22],
+        );
+        buf.extend_from_slice(b"SSH-2.0");
+        assert_eq!(
+            parse_header(&buf, ProxyProtocol::V2),
+            Ok(Some(Header {
+                len: 28,
+                addrs: addrs("192.0.2.1:40000", "198.51.100.1:22"),
+            }))
+        );
+        assert_eq!(parse_header(&buf[..20], ProxyProtocol::V2), Ok(None));
+        assert!(parse_header(&buf, ProxyProtocol::V1).is_err());
+
+        let mut addrs6 = [0; 36];
+        addrs6[15] = 1;
+        addrs6[31] = 2;
+        addrs6[33] = 1;
+        addrs6[35] = 2;
+        let buf = v2_header(V2_CMD_PROXY, V2_TCP6, &addrs6);
+        assert_eq!(
+            parse_header(&buf, ProxyProtocol::Optional)
+                .unwrap()
+                .unwrap()
+                .addrs,
+            addrs("[::1]:1", "[::2]:2")
+        );
+
+        // Health check of the load balancer
+        let buf = v2_header(V2_CMD_LOCAL, 0, &[]);
+        assert_eq!(
+            parse_header(&buf, ProxyProtocol::V2),
+            Ok(Some(Header {
+                len: 16,
+                addrs: None
+            }))
+        );
+
+        assert!(parse_header(
+            &v2_header(V2_CMD_PROXY, V2_TCP4, &[0; 8]),
+            ProxyProtocol::V2
+        )
+        .is_err());
+        assert!(parse_header(&v2_header(2, V2_TCP4, &[0; 12]), ProxyProtocol::V2).is_err());
+        let mut buf = v2_header(V2_CMD_PROXY, V2_TCP4, &[0; 12]);
+        buf[12] = 0x11;
+        assert!(parse_header(&buf, ProxyProtocol::V2).is_err());
+    }
+
+    #[test]
+    fn optional() {
+        assert_eq!(
+            parse_header(b"GET / HTTP/1.1\r\n", ProxyProtocol::Optional),
+            Ok(Some(Header {
+                len: 0,
+                addrs: None
+            }))
+        );
+        // Could still be either header
+        assert_eq!(parse_header(b"", ProxyProtocol::Optional), Ok(None));
+        assert_eq!(parse_header(b"\r\n", ProxyProtocol::Optional), Ok(None));
+        assert!(parse_header(b"GET / HTTP/1.1\r\n", ProxyProtocol::V1).is_err());
+    }
+}
@@ -0,0 +1,105 @@
+use std::time::Instant;
+
+use crate::config::Cps;
+
+/// Token bucket limiting the rate of accepted connections, which disables the service for a
+/// while once the bucket runs dry
+#[derive(Debug)]
+pub(crate) struct RateLimiter {
+    cps: Cps,
+    tokens: f64,
+    last_refill: Instant,
+    disabled_until: Option<Instant>,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(cps: Cps, now: Instant) -> Self {
+        Self {
+            cps,
+            tokens: cps.burst as f64,
+            last_refill: now,
+            disabled_until: None,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last_refill);
+        self.tokens =
+            (self.tokens + elapsed.as_secs_f64() * self.cps.rate as f64).min(self.cps.burst as f64);
+        self.last_refill = now;
+    }
+
+    /// Take a token for a new connection.
+    ///
+    /// Returns `false` if the rate was exceeded; the service is then disabled until
+    /// [Self::disabled_until].
+    pub(crate) fn try_acquire(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            self.disabled_until = Some(now + self.cps.disable);
+            false
+        }
+    }
+
+    /// End of the current disable period, if any
+    pub(crate) fn disabled_until(&self, now: Instant) -> Option<Instant> {
+        self.disabled_until.filter(|&until| until > now)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::*;
+
+    const CPS: Cps = Cps {
+        rate: 2,
+        disable: Duration::from_secs(10),
+        burst: 3,
+    };
+
+    #[test]
+    fn burst_then_disable() {
+        let start = Instant::now();
+        let mut limiter = RateLimiter::new(CPS, start);
+        for _ in 0..3 {
+            assert!(limiter.try_acquire(start));
+        }
+        assert_eq!(limiter.disabled_until(start), None);
+
+        assert!(!limiter.try_acquire(start));
+        assert_eq!(
+            limiter.disabled_until(start),
+            Some(start + Duration::from_secs(10))
+        );
+        assert_eq!(
+            limiter.disabled_until(start + Duration::from_secs(10)),
+            None
+        );
+    }
+
+    #[test]
+    fn refill() {
+        let start = Instant::now();
+        let mut limiter = RateLimiter::new(CPS, start);
+        for _ in 0..3 {
+            assert!(limiter.try_acquire(start));
+        }
+
+        // 2 connections per second
+        let later = start + Duration::from_millis(500);
+        assert!(limiter.try_acquire(later));
+        assert!(!limiter.try_acquire(later));
+
+        // refill never exceeds the burst
+        let much_later = start + Duration::from_secs(60);
+        for _ in 0..3 {
+            assert!(limiter.try_acquire(much_later));
+        }
+        assert!(!limiter.try_acquire(much_later));
+    }
+}
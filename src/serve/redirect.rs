@@ -0,0 +1,359 @@
+use std::{
+    io::{self, Read, Write},
+    net::{IpAddr, Shutdown, SocketAddr, ToSocketAddrs},
+    time::{Duration, Instant},
+};
+
+use mio::{net::TcpStream, Interest, Registry, Token};
+
+use super::{
+    connection::{Connection, Stream},
+    ConnectionToken,
+};
+use crate::{error::StdIoErrorExt, service::Service};
+
+/// How long connecting to the target may take before the client is disconnected
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Bytes buffered in each direction
+const BUF_LEN: usize = 16 * 1024;
+
+/// Bytes transferred per event, so one fast connection cannot starve the others
+const MAX_TRANSFER_PER_EVENT: usize = 64 * 1024;
+
+/// Resolve the `redirect` target of `service`; other services have no addresses.
+///
+/// A target without addresses is an error, so the service does not accept clients it cannot
+/// forward.
+pub(crate) fn resolve(service: &Service) -> crate::Result<Vec<SocketAddr>> {
+    let redirect = match &service.redirect {
+        Some(redirect) => redirect,
+        None => return Ok(Vec::new()),
+    };
+    (redirect.host.as_str(), redirect.port)
+        .to_socket_addrs()
+        .and_then(|addrs| {
+            let addrs: Vec<SocketAddr> = addrs.collect();
+            if addrs.is_empty() {
+                return Err(io::Error::new(io::ErrorKind::NotFound, "no addresses"));
+            }
+            Ok(addrs)
+        })
+        .with_message(format!(
+            "failed to resolve redirect target {} of service {:?}",
+            redirect, service.name
+        ))
+}
+
+/// Outbound connection to a redirect target, trying its addresses in turn
+pub(crate) struct Target {
+    stream: TcpStream,
+    /// Addresses to try if connecting to the current one fails
+    remaining: Vec<SocketAddr>,
+    connected: bool,
+}
+
+impl Target {
+    /// Start connecting to the first of `addrs` that accepts a connection attempt
+    pub(crate) fn connect(addrs: &[SocketAddr]) -> io::Result<Self> {
+        let mut remaining = addrs.to_vec();
+        remaining.reverse();
+        let stream = connect_next(&mut remaining)?;
+        Ok(Self {
+            stream,
+            remaining,
+            connected: false,
+        })
+    }
+}
+
+fn connect_next(remaining: &mut Vec<SocketAddr>) -> io::Result<TcpStream> {
+    let mut last_err = None;
+    while let Some(addr) = remaining.pop() {
+        match TcpStream::connect(addr) {
+            Ok(stream) => return Ok(stream),
+            Err(err) => last_err = Some(err),
+        }
+    }
+    Err(last_err
+        .unwrap_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no address to connect to")))
+}
+
+/// Stream whose write side can be shut down, to pass on a half-close
+trait HalfClose: Write {
+    fn shutdown_write(&self) -> io::Result<()>;
+}
+
+impl HalfClose for Stream {
+    fn shutdown_write(&self) -> io::Result<()> {
+        match self {
+            Self::Tcp(stream) => stream.shutdown(Shutdown::Write),
+            Self::Unix(stream) => stream.shutdown(Shutdown::Write),
+        }
+    }
+}
+
+impl HalfClose for TcpStream {
+    fn shutdown_write(&self) -> io::Result<()> {
+        self.shutdown(Shutdown::Write)
+    }
+}
+
+/// Bytes flowing in one direction of a redirect connection
+struct Direction {
+    buf: Box<[u8]>,
+    /// Bytes read but not written yet are `buf[start..end]`
+    start: usize,
+    end: usize,
+    /// Whether the reading side sent EOF
+    eof: bool,
+    /// Whether the EOF was passed on to the writing side
+    shut_down: bool,
+}
+
+impl Default for Direction {
+    fn default() -> Self {
+        Self {
+            buf: vec![0; BUF_LEN].into_boxed_slice(),
+            start: 0,
+            end: 0,
+            eof: false,
+            shut_down: false,
+        }
+    }
+}
+
+impl Direction {
+    /// Read from `from` when the buffer is empty, and write the buffer to `to`. Once `from` sent
+    /// EOF and the buffer is written, shut down the write side of `to`.
+    ///
+    /// Returns the number of bytes read and written.
+    fn transfer(&mut self, from: &mut impl Read, to: &mut impl HalfClose) -> io::Result<usize> {
+        let mut transferred = 0;
+        if self.start == self.end && !self.eof {
+            self.start = 0;
+            self.end = 0;
+            match from.read(&mut self.buf) {
+                Ok(0) => self.eof = true,
+                Ok(len) => {
+                    self.end = len;
+                    transferred += len;
+                }
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {}
+                Err(err) => return Err(err),
+            }
+        }
+        if self.start < self.end {
+            match to.write(&self.buf[self.start..self.end]) {
+                Ok(len) => {
+                    self.start += len;
+                    transferred += len;
+                }
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {}
+                Err(err) => return Err(err),
+            }
+        }
+        if self.eof && self.start == self.end && !self.shut_down {
+            match to.shutdown_write() {
+                // The peer may have closed already
+                Ok(()) => {}
+                Err(ref err) if err.kind() == io::ErrorKind::NotConnected => {}
+                Err(err) => return Err(err),
+            }
+            self.shut_down = true;
+        }
+        Ok(transferred)
+    }
+}
+
+/// Connection of a `redirect` service, spliced to its target by the event loop.
+///
+/// Both streams are registered with the same token, since any event transfers data both ways.
+pub(crate) struct RedirectConnection {
+    client: Connection,
+    target: Target,
+    token: ConnectionToken,
+    to_target: Direction,
+    to_client: Direction,
+    /// Time by which the target must accept the connection
+    deadline: Option<Instant>,
+}
+
+impl RedirectConnection {
+    pub(crate) fn new(client: Connection, target: Target) -> Self {
+        Self {
+            token: ConnectionToken::new(),
+            client,
+            target,
+            to_target: Direction::default(),
+            to_client: Direction::default(),
+            deadline: Some(Instant::now() + CONNECT_TIMEOUT),
+        }
+    }
+
+    pub(crate) fn token(&self) -> Token {
+        self.token.token()
+    }
+
+    pub(crate) fn peer_ip(&self) -> Option<IpAddr> {
+        self.client.peer_addr.map(|peer_addr| peer_addr.ip())
+    }
+
+    /// Client of the connection, for logging
+    pub(crate) fn peer(&self) -> &Connection {
+        &self.client
+    }
+
+    pub(crate) fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// Whether the target accepted the connection
+    pub(crate) fn is_connected(&self) -> bool {
+        self.target.connected
+    }
+
+    pub(crate) fn register(&mut self, registry: &Registry) -> io::Result<()> {
+        let interests = Interest::READABLE | Interest::WRITABLE;
+        registry.register(&mut self.client.stream, self.token.token(), interests)?;
+        registry.register(&mut self.target.stream, self.token.token(), interests)
+    }
+
+    pub(crate) fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        registry.deregister(&mut self.client.stream)?;
+        registry.deregister(&mut self.target.stream)
+    }
+
+    /// Transfer data both ways until the connection would block.
+    ///
+    /// Returns whether the connection is still open: it closes once both sides sent EOF.
+    pub(crate) fn handle(&mut self, registry: &Registry) -> io::Result<bool> {
+        if !self.target.connected && !self.finish_connect(registry)? {
+            return Ok(true);
+        }
+
+        let mut transferred = 0;
+        loop {
+            let moved = self
+                .to_target
+                .transfer(&mut self.client.stream, &mut self.target.stream)?
+                + self
+                    .to_client
+                    .transfer(&mut self.target.stream, &mut self.client.stream)?;
+            if self.to_target.shut_down && self.to_client.shut_down {
+                return Ok(false);
+            }
+            if moved == 0 {
+                return Ok(true);
+            }
+
+            transferred += moved;
+            if transferred >= MAX_TRANSFER_PER_EVENT {
+                // Events are edge-triggered: re-arm them, since the connection may not block
+                let interests = Interest::READABLE | Interest::WRITABLE;
+                registry.reregister(&mut self.client.stream, self.token.token(), interests)?;
+                registry.reregister(&mut self.target.stream, self.token.token(), interests)?;
+                return Ok(true);
+            }
+        }
+    }
+
+    /// Check whether connecting to the target finished, trying its next address if it failed.
+    ///
+    /// Returns whether the target is connected.
+    fn finish_connect(&mut self, registry: &Registry) -> io::Result<bool> {
+        let err = match self.target.stream.take_error()? {
+            Some(err) => err,
+            None => match self.target.stream.peer_addr() {
+                Ok(_) => {
+                    self.target.connected = true;
+                    self.deadline = None;
+                    return Ok(true);
+                }
+                Err(ref err) if err.kind() == io::ErrorKind::NotConnected => return Ok(false),
+                Err(err) => err,
+            },
+        };
+        if self.target.remaining.is_empty() {
+            return Err(err);
+        }
+        registry.deregister(&mut self.target.stream)?;
+        self.target.stream = connect_next(&mut self.target.remaining)?;
+        registry.register(
+            &mut self.target.stream,
+            self.token.token(),
+            Interest::READABLE | Interest::WRITABLE,
+        )?;
+        Ok(false)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Reader that returns its chunks, then EOF
+    struct Chunks(Vec<Vec<u8>>);
+
+    impl Read for Chunks {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.0.is_empty() {
+                return Ok(0);
+            }
+            let chunk = self.0.remove(0);
+            buf[..chunk.len()].copy_from_slice(&chunk);
+            Ok(chunk.len())
+        }
+    }
+
+    /// Writer that accepts at most `limit` bytes per write
+    #[derive(Default)]
+    struct Sink {
+        written: Vec<u8>,
+        limit: usize,
+    }
+
+    impl Write for Sink {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let len = buf.len().min(self.limit);
+            if len == 0 {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            self.written.extend_from_slice(&buf[..len]);
+            Ok(len)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl HalfClose for Sink {
+        fn shutdown_write(&self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn half_close() {
+        let mut from = Chunks(vec![b"hello ".to_vec(), b"world".to_vec()]);
+        let mut to = Sink {
+            limit: 4,
+            ..Sink::default()
+        };
+        let mut direction = Direction::default();
+        while !direction.shut_down {
+            direction.transfer(&mut from, &mut to).unwrap();
+        }
+        assert_eq!(to.written, b"hello world");
+        assert!(direction.eof);
+
+        // Nothing is read while the target does not accept writes
+        let mut from = Chunks(vec![b"a".to_vec(), b"b".to_vec()]);
+        let mut blocked = Sink::default();
+        let mut direction = Direction::default();
+        assert_eq!(direction.transfer(&mut from, &mut blocked).unwrap(), 1);
+        assert_eq!(direction.transfer(&mut from, &mut blocked).unwrap(), 0);
+        assert!(!direction.eof);
+    }
+}
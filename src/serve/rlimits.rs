@@ -0,0 +1,102 @@
+use std::{convert::TryFrom, io};
+
+use crate::service::Service;
+
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+type Resource = libc::__rlimit_resource_t;
+#[cfg(not(all(target_os = "linux", target_env = "gnu")))]
+type Resource = libc::c_int;
+
+/// Resource limits of a service (`rlimit_*` options), set as both soft and hard limits
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ResourceLimits(Vec<(Resource, libc::rlim_t)>);
+
+impl ResourceLimits {
+    pub(crate) fn for_service(service: &Service) -> Self {
+        fn limit(value: Option<u64>) -> libc::rlim_t {
+            value
+                .and_then(|value| libc::rlim_t::try_from(value).ok())
+                .unwrap_or(libc::RLIM_INFINITY)
+        }
+
+        let limits = [
+            (libc::RLIMIT_AS, service.rlimit_as.map(|size| size.0)),
+            (libc::RLIMIT_CPU, service.rlimit_cpu.map(|time| time.0)),
+            (libc::RLIMIT_DATA, service.rlimit_data.map(|size| size.0)),
+            (libc::RLIMIT_RSS, service.rlimit_rss.map(|size| size.0)),
+            (libc::RLIMIT_STACK, service.rlimit_stack.map(|size| size.0)),
+            (
+                libc::RLIMIT_NOFILE,
+                service.rlimit_files.map(|files| files.0.map(u64::from)),
+            ),
+            (libc::RLIMIT_CORE, service.rlimit_core.map(|size| size.0)),
+        ];
+        Self(
+            limits
+                .iter()
+                .filter_map(|&(resource, value)| Some((resource, limit(value?))))
+                .collect(),
+        )
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Soft and hard limit of each limited resource; the others keep both limits of yinetd
+    fn rlimits(&self) -> impl Iterator<Item = (Resource, libc::rlimit)> + '_ {
+        self.0.iter().map(|&(resource, limit)| {
+            let rlimit = libc::rlimit {
+                rlim_cur: limit,
+                rlim_max: limit,
+            };
+            (resource, rlimit)
+        })
+    }
+
+    /// Set the limits. Called in the child before exec.
+    pub(crate) fn apply(&self) -> io::Result<()> {
+        for (resource, rlimit) in self.rlimits() {
+            if unsafe { libc::setrlimit(resource, &rlimit) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::parse::parse_test_service;
+
+    fn rlimits(options: &str) -> Vec<(Resource, libc::rlim_t, libc::rlim_t)> {
+        ResourceLimits::for_service(&parse_test_service(options))
+            .rlimits()
+            .map(|(resource, rlimit)| (resource, rlimit.rlim_cur, rlimit.rlim_max))
+            .collect()
+    }
+
+    #[test]
+    fn for_service() {
+        assert!(ResourceLimits::default().is_empty());
+        assert_eq!(rlimits(""), vec![]);
+
+        // A value is both the soft and the hard limit
+        assert_eq!(
+            rlimits("rlimit_as = 64M\n rlimit_cpu = 30s\n rlimit_files = 256"),
+            vec![
+                (libc::RLIMIT_AS, 64 << 20, 64 << 20),
+                (libc::RLIMIT_CPU, 30, 30),
+                (libc::RLIMIT_NOFILE, 256, 256),
+            ]
+        );
+        assert_eq!(
+            rlimits("rlimit_stack = UNLIMITED\n rlimit_core = 0"),
+            vec![
+                (libc::RLIMIT_STACK, libc::RLIM_INFINITY, libc::RLIM_INFINITY),
+                (libc::RLIMIT_CORE, 0, 0),
+            ]
+        );
+    }
+}
@@ -0,0 +1,130 @@
+use std::{ffi::CString, io};
+
+use nix::{
+    sched::{sched_setaffinity, CpuSet},
+    unistd::Pid,
+};
+
+use crate::{
+    config::{CpuList, IoprioClass},
+    error::nix_to_io,
+    service::Service,
+};
+
+const OOM_SCORE_ADJ_PATH: &str = "/proc/self/oom_score_adj";
+
+/// `which` argument of ioprio_set(2) for a process
+const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+
+/// Scheduling attributes of a service (`nice`, `ioprio`, `cpu_affinity`, `oom_score_adj`).
+///
+/// Everything is prepared in the parent, so the child only makes system calls.
+#[derive(Debug, Clone)]
+pub(crate) struct Scheduling {
+    nice: Option<libc::c_int>,
+    /// Encoded class and level
+    ioprio: Option<libc::c_int>,
+    cpu_set: Option<CpuSet>,
+    /// Path and contents to write
+    oom_score_adj: Option<(CString, String)>,
+}
+
+impl Scheduling {
+    /// Scheduling attributes of `service`; `None` if it has none
+    pub(crate) fn for_service(service: &Service) -> Option<Self> {
+        let ioprio = match (service.ioprio_class, service.ioprio) {
+            (None, None) => None,
+            (class, level) => Some(encode_ioprio(
+                class.unwrap_or(IoprioClass::BestEffort),
+                level.map_or(0, |level| level.0),
+            )),
+        };
+        let scheduling = Self {
+            nice: service.nice.map(|nice| nice.0),
+            ioprio,
+            cpu_set: service.cpu_affinity.as_ref().map(cpu_set),
+            oom_score_adj: service.oom_score_adj.map(|adj| {
+                let path = CString::new(OOM_SCORE_ADJ_PATH).expect("path has no NUL");
+                (path, adj.0.to_string())
+            }),
+        };
+        if scheduling.nice.is_none()
+            && scheduling.ioprio.is_none()
+            && scheduling.cpu_set.is_none()
+            && scheduling.oom_score_adj.is_none()
+        {
+            return None;
+        }
+        Some(scheduling)
+    }
+
+    /// Set the attributes on the calling process. Called in the child before exec, while it is
+    /// still privileged.
+    pub(crate) fn apply(&self) -> io::Result<()> {
+        if let Some(nice) = self.nice {
+            if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, nice) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        if let Some(ioprio) = self.ioprio {
+            if unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, ioprio) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        if let Some(cpu_set) = &self.cpu_set {
+            sched_setaffinity(Pid::from_raw(0), cpu_set).map_err(nix_to_io)?;
+        }
+        if let Some((path, adj)) = &self.oom_score_adj {
+            write_file(path, adj.as_bytes())?;
+        }
+        Ok(())
+    }
+}
+
+fn encode_ioprio(class: IoprioClass, level: u8) -> libc::c_int {
+    let class = match class {
+        IoprioClass::RealTime => 1,
+        IoprioClass::BestEffort => 2,
+        IoprioClass::Idle => 3,
+    };
+    (class << IOPRIO_CLASS_SHIFT) | level as libc::c_int
+}
+
+fn cpu_set(cpus: &CpuList) -> CpuSet {
+    let mut cpu_set = CpuSet::new();
+    for &cpu in cpus.0.iter() {
+        cpu_set
+            .set(cpu)
+            .expect("CPU lists are checked to fit in a CPU set");
+    }
+    cpu_set
+}
+
+/// Write `contents` to an existing file without allocating
+fn write_file(path: &CString, contents: &[u8]) -> io::Result<()> {
+    let fd = unsafe { libc::open(path.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let written =
+        unsafe { libc::write(fd, contents.as_ptr() as *const libc::c_void, contents.len()) };
+    let result = if written < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    };
+    unsafe { libc::close(fd) };
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn ioprio() {
+        assert_eq!(encode_ioprio(IoprioClass::BestEffort, 4), (2 << 13) | 4);
+        assert_eq!(encode_ioprio(IoprioClass::Idle, 0), 3 << 13);
+    }
+}
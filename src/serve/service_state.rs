@@ -1,48 +1,657 @@
-use std::process::Child;
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, SocketAddr},
+    os::unix::io::AsRawFd,
+    process::{Child, ExitStatus},
+    time::Instant,
+};
 
-use log::info;
+use log::{debug, error, info, warn};
+use mio::{Interest, Registry, Token};
+use nix::{
+    sys::signal::{kill, Signal},
+    unistd::Pid,
+};
 
-use super::{ProtoBinder, Service};
+use super::{
+    access::AccessControl,
+    access_times::{AccessWindows, LocalClock},
+    connection::Connection,
+    internal::{InternalConnection, Progress, TcpmuxRequest},
+    listener::Listener,
+    proxy_protocol::ProxyConnection,
+    rate_limit::RateLimiter,
+    redirect::{self, RedirectConnection},
+    set_fd_nonblocking,
+    tcp_wrappers::{TcpWrappers, Verdict},
+    Service,
+};
+use crate::{
+    config::LimitAction,
+    error::{error_chain, StdIoErrorExt},
+};
 
-pub(crate) struct ServiceState<'a, P: ProtoBinder> {
-    // todo(tmfink): reap child processes
-    child_procs: Vec<Child>,
-    pub(crate) service: &'a Service,
-    pub(crate) proto_binder: P,
+/// Live child process of a service
+struct ChildProc {
+    child: Child,
+    /// Address of the client the child is serving, for inet connections
+    peer_ip: Option<IpAddr>,
 }
 
-impl<'a, P: ProtoBinder> ServiceState<'a, P> {
-    pub(crate) fn new(service: &'a Service, proto_binder: P) -> Self {
+/// Connection handed back to the event loop by a service
+pub(crate) enum Handoff {
+    /// A TCPMUX client named the service it wants
+    Tcpmux(TcpmuxRequest),
+    /// The PROXY header was read; the connection has the addresses of the header
+    Proxied(Connection),
+}
+
+pub(crate) struct ServiceState {
+    /// Live children, by PID
+    child_procs: HashMap<u32, ChildProc>,
+    /// Open connections of an INTERNAL service, by token
+    internal_connections: HashMap<Token, InternalConnection>,
+    /// Open connections of a `redirect` service, by token
+    redirect_connections: HashMap<Token, RedirectConnection>,
+    /// Accepted connections waiting for their `proxy_protocol` header, by token
+    proxy_connections: HashMap<Token, ProxyConnection>,
+    /// Resolved `redirect` address
+    redirect_addrs: Vec<SocketAddr>,
+    /// PID of the child that currently owns the service socket (i.e. "wait" mode).
+    /// The socket must not be polled while this is set.
+    wait_child: Option<u32>,
+    /// Whether the listener is currently registered with mio
+    registered: bool,
+    /// Connection rate limit (`cps`)
+    rate_limiter: Option<RateLimiter>,
+    /// Client address rules (`only_from`, `no_access`)
+    access: AccessControl,
+    /// Time windows during which connections are accepted (`access_times`)
+    access_windows: Option<AccessWindows>,
+    /// Rule files, if `tcp_wrappers` is enabled
+    tcp_wrappers: Option<TcpWrappers>,
+    pub(crate) service: Service,
+    /// Bound socket; `None` once the service stopped accepting, and for TCPMUX services
+    pub(crate) listener: Option<Listener>,
+    /// Whether a reload removed the service, which is only kept until its children exit
+    pub(crate) removed: bool,
+}
+
+impl ServiceState {
+    pub(crate) fn new(
+        service: Service,
+        listener: Option<Listener>,
+        redirect_addrs: Vec<SocketAddr>,
+    ) -> Self {
+        let rate_limiter = rate_limiter(&service);
+        let access = AccessControl::new(&service);
+        let access_windows = access_windows(&service);
+        let tcp_wrappers = TcpWrappers::for_service(&service);
         Self {
             service,
-            proto_binder,
-            child_procs: Vec::new(),
+            listener,
+            child_procs: HashMap::new(),
+            internal_connections: HashMap::new(),
+            redirect_connections: HashMap::new(),
+            proxy_connections: HashMap::new(),
+            redirect_addrs,
+            wait_child: None,
+            registered: false,
+            rate_limiter,
+            access,
+            access_windows,
+            tcp_wrappers,
+            removed: false,
+        }
+    }
+
+    /// Replace the service definition and listener, keeping track of existing children and
+    /// connections
+    pub(crate) fn replace(
+        &mut self,
+        service: Service,
+        listener: Option<Listener>,
+        redirect_addrs: Vec<SocketAddr>,
+    ) {
+        assert!(self.listener.is_none() && !self.registered);
+        self.rate_limiter = rate_limiter(&service);
+        self.access = AccessControl::new(&service);
+        self.access_windows = access_windows(&service);
+        self.tcp_wrappers = TcpWrappers::for_service(&service);
+        self.redirect_addrs = redirect_addrs;
+        self.service = service;
+        self.listener = listener;
+        // A "wait" child keeps the old socket, which no longer blocks the new one
+        self.wait_child = None;
+    }
+
+    /// Read the hosts file and tcp wrappers rule files again and resolve the `redirect` target,
+    /// for a reload that keeps the service
+    pub(crate) fn reload_files(&mut self) {
+        self.access = AccessControl::new(&self.service);
+        self.tcp_wrappers = TcpWrappers::for_service(&self.service);
+        match redirect::resolve(&self.service) {
+            Ok(redirect_addrs) => self.redirect_addrs = redirect_addrs,
+            Err(err) => warn!(
+                "{}, keeping the previous addresses {:?}",
+                error_chain(&err),
+                self.redirect_addrs
+            ),
+        }
+    }
+
+    /// Whether the service serves clients: its listener is bound, unless it is a TCPMUX service,
+    /// and its `redirect` target resolved
+    pub(crate) fn is_started(&self) -> bool {
+        (self.listener.is_some() || self.service.is_tcpmux())
+            && (self.service.redirect.is_none() || !self.redirect_addrs.is_empty())
+    }
+
+    pub(crate) fn add_child(&mut self, child: Child, peer_ip: Option<IpAddr>) {
+        self.child_procs
+            .insert(child.id(), ChildProc { child, peer_ip });
+    }
+
+    /// Serve a connection of an INTERNAL service from the event loop
+    pub(crate) fn add_internal_connection(
+        &mut self,
+        registry: &Registry,
+        mut connection: InternalConnection,
+    ) -> crate::Result<()> {
+        connection.register(registry).with_message(format!(
+            "failed to register service {:?} connection with mio",
+            self.service.name
+        ))?;
+        self.internal_connections
+            .insert(connection.token(), connection);
+        Ok(())
+    }
+
+    /// Forward a connection of a `redirect` service from the event loop
+    pub(crate) fn add_redirect_connection(
+        &mut self,
+        registry: &Registry,
+        mut connection: RedirectConnection,
+    ) -> crate::Result<()> {
+        if let Err(err) = connection.register(registry) {
+            let _ = connection.deregister(registry);
+            return Err(err).with_message(format!(
+                "failed to register service {:?} connection with mio",
+                self.service.name
+            ));
+        }
+        self.redirect_connections
+            .insert(connection.token(), connection);
+        Ok(())
+    }
+
+    /// Wait for the PROXY header of an accepted connection from the event loop
+    pub(crate) fn add_proxy_connection(
+        &mut self,
+        registry: &Registry,
+        mut connection: ProxyConnection,
+    ) -> crate::Result<()> {
+        connection.register(registry).with_message(format!(
+            "failed to register service {:?} connection with mio",
+            self.service.name
+        ))?;
+        self.proxy_connections
+            .insert(connection.token(), connection);
+        Ok(())
+    }
+
+    /// Addresses of the `redirect` target
+    pub(crate) fn redirect_addrs(&self) -> &[SocketAddr] {
+        &self.redirect_addrs
+    }
+
+    /// Whether `token` belongs to an internal, redirect or proxy connection of this service
+    pub(crate) fn has_connection(&self, token: Token) -> bool {
+        self.internal_connections.contains_key(&token)
+            || self.proxy_connections.contains_key(&token)
+            || self.redirect_connections.contains_key(&token)
+    }
+
+    /// Serve the internal, redirect or proxy connection with `token`, closing it when it is
+    /// done.
+    ///
+    /// A connection of the tcpmux service is returned, without its mio registration, once the
+    /// client named the service it wants, and so is a proxy connection once its header was read.
+    pub(crate) fn handle_connection_event(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+    ) -> Option<Handoff> {
+        if self.proxy_connections.contains_key(&token) {
+            return self
+                .handle_proxy_event(registry, token)
+                .map(Handoff::Proxied);
+        }
+        if !self.internal_connections.contains_key(&token) {
+            self.handle_redirect_event(registry, token);
+            return None;
+        }
+        let connection = self.internal_connections.get_mut(&token)?;
+        let progress = connection.handle(registry).unwrap_or_else(|err| {
+            debug!("Service {:?} connection failed: {}", self.service.name, err);
+            Progress::Closed
+        });
+        let name = match progress {
+            Progress::Open => return None,
+            Progress::Closed => None,
+            Progress::Tcpmux(name) => Some(name),
+        };
+        let mut connection = self.internal_connections.remove(&token)?;
+        let _ = connection.deregister(registry);
+        name.map(|name| {
+            Handoff::Tcpmux(TcpmuxRequest {
+                name,
+                connection: connection.into_connection(),
+            })
+        })
+    }
+
+    fn handle_proxy_event(&mut self, registry: &Registry, token: Token) -> Option<Connection> {
+        let connection = self.proxy_connections.get_mut(&token)?;
+        let header_read = match connection.read_header() {
+            Ok(false) => return None,
+            Ok(true) => true,
+            Err(err) if err.kind() == io::ErrorKind::InvalidData => {
+                error!(
+                    "Service {:?} rejected connection from {}: {}",
+                    self.service.name,
+                    connection.peer(),
+                    err
+                );
+                false
+            }
+            Err(err) => {
+                debug!(
+                    "Service {:?} connection from {} failed before its PROXY header: {}",
+                    self.service.name,
+                    connection.peer(),
+                    err
+                );
+                false
+            }
+        };
+        let mut connection = self.proxy_connections.remove(&token)?;
+        let _ = connection.deregister(registry);
+        if !header_read {
+            return None;
+        }
+        Some(connection.into_connection())
+    }
+
+    fn handle_redirect_event(&mut self, registry: &Registry, token: Token) {
+        let connection = match self.redirect_connections.get_mut(&token) {
+            Some(connection) => connection,
+            None => return,
+        };
+        let open = match connection.handle(registry) {
+            Ok(open) => open,
+            Err(err) if !connection.is_connected() => {
+                warn!(
+                    "Service {:?} failed to connect to {}: {}",
+                    self.service.name,
+                    redirect_target(&self.service),
+                    err
+                );
+                false
+            }
+            Err(err) => {
+                debug!("Service {:?} connection failed: {}", self.service.name, err);
+                false
+            }
+        };
+        if !open {
+            if let Some(mut connection) = self.redirect_connections.remove(&token) {
+                let _ = connection.deregister(registry);
+            }
         }
     }
-    pub(crate) fn add_child(&mut self, child: Child) {
-        self.child_procs.push(child)
+
+    /// Close the internal connections whose client did not send what the service waits for in
+    /// time, the redirect connections whose target did not accept them in time, and the proxy
+    /// connections whose header did not arrive in time
+    fn expire_connections(&mut self, registry: &Registry, now: Instant) {
+        let name = &self.service.name;
+        self.internal_connections.retain(|_, connection| {
+            if connection.deadline().is_none_or(|deadline| deadline > now) {
+                return true;
+            }
+            debug!(
+                "Service {:?} closing connection from {} that timed out",
+                name,
+                connection.peer()
+            );
+            let _ = connection.deregister(registry);
+            false
+        });
+        self.proxy_connections.retain(|_, connection| {
+            if connection.deadline() > now {
+                return true;
+            }
+            warn!(
+                "Service {:?} closing connection from {} that sent no PROXY header in time",
+                name,
+                connection.peer()
+            );
+            let _ = connection.deregister(registry);
+            false
+        });
+        let target = redirect_target(&self.service);
+        self.redirect_connections.retain(|_, connection| {
+            if connection.deadline().is_none_or(|deadline| deadline > now) {
+                return true;
+            }
+            warn!(
+                "Service {:?} timed out connecting to {}, closing connection from {}",
+                name,
+                target,
+                connection.peer()
+            );
+            let _ = connection.deregister(registry);
+            false
+        });
+    }
+
+    /// Close all internal, redirect and proxy connections
+    pub(crate) fn close_connections(&mut self, registry: &Registry) {
+        for (_, mut connection) in self.internal_connections.drain() {
+            let _ = connection.deregister(registry);
+        }
+        for (_, mut connection) in self.redirect_connections.drain() {
+            let _ = connection.deregister(registry);
+        }
+        for (_, mut connection) in self.proxy_connections.drain() {
+            let _ = connection.deregister(registry);
+        }
+    }
+
+    /// Add a child that was handed the service socket itself
+    pub(crate) fn add_wait_child(&mut self, child: Child) {
+        assert!(self.wait_child.is_none());
+        self.wait_child = Some(child.id());
+        self.add_child(child, None)
+    }
+
+    /// Whether the access rules allow a client with address `ip`
+    pub(crate) fn allows_client(&self, ip: IpAddr) -> bool {
+        self.access.allows(ip)
+    }
+
+    /// Whether the current time is within the service `access_times`
+    pub(crate) fn in_access_times(&self) -> bool {
+        self.access_windows
+            .as_ref()
+            .is_none_or(|access_windows| access_windows.is_open())
     }
 
-    pub(crate) fn _children_count(&self) -> usize {
+    /// Whether the listener is deregistered outside the `access_times`, instead of rejecting
+    /// connections
+    fn pauses_outside_access_times(&self) -> bool {
+        self.service.access_times_action == LimitAction::Pause || self.service.wait_mode()
+    }
+
+    /// Check an inet client against the tcp wrappers rules, if they are enabled
+    pub(crate) fn check_tcp_wrappers(
+        &self,
+        client: SocketAddr,
+        server: Option<SocketAddr>,
+    ) -> Option<Verdict> {
+        let tcp_wrappers = self.tcp_wrappers.as_ref()?;
+        Some(tcp_wrappers.check(&self.service.name, client, server, self.access.hosts()))
+    }
+
+    pub(crate) fn is_waiting(&self) -> bool {
+        self.wait_child.is_some()
+    }
+
+    pub(crate) fn children_count(&self) -> usize {
+        self.child_procs.len()
+    }
+
+    /// Number of connections being served: children, internal and redirect connections, and
+    /// connections waiting for their PROXY header
+    pub(crate) fn instances_count(&self) -> usize {
         self.child_procs.len()
+            + self.internal_connections.len()
+            + self.redirect_connections.len()
+            + self.proxy_connections.len()
+    }
+
+    /// Whether the service serves as many connections as its `instances` limit allows
+    pub(crate) fn at_instance_limit(&self) -> bool {
+        match self.service.instances.0 {
+            Some(instances) => self.instances_count() >= instances as usize,
+            None => false,
+        }
+    }
+
+    /// Whether the service serves as many connections from `peer_ip` as its `per_source` limit
+    /// allows
+    pub(crate) fn at_per_source_limit(&self, peer_ip: IpAddr) -> bool {
+        let per_source = match self.service.per_source.0 {
+            Some(per_source) => per_source as usize,
+            None => return false,
+        };
+        let peer_children = self
+            .child_procs
+            .values()
+            .map(|child_proc| child_proc.peer_ip)
+            .chain(
+                self.internal_connections
+                    .values()
+                    .map(|connection| connection.peer_ip()),
+            )
+            .chain(
+                self.redirect_connections
+                    .values()
+                    .map(|connection| connection.peer_ip()),
+            )
+            .filter(|&ip| ip == Some(peer_ip))
+            .count();
+        peer_children >= per_source
     }
 
-    /// Reap child processes that have exited
-    pub(crate) fn try_reap_children(&mut self) {
-        let mut new_children = Vec::new();
-        for mut child in self.child_procs.drain(..) {
-            match child.try_wait() {
-                Ok(Some(status)) => info!(
+    /// Take a connection from the rate limit.
+    ///
+    /// Returns `false` if the service exceeded its `cps` rate; it is then disabled for a while.
+    pub(crate) fn try_acquire_rate(&mut self) -> bool {
+        let rate_limiter = match &mut self.rate_limiter {
+            Some(rate_limiter) => rate_limiter,
+            None => return true,
+        };
+        rate_limiter.try_acquire(Instant::now())
+    }
+
+    /// End of the `cps` backoff, if the service is disabled by its rate limit
+    fn rate_disabled_until(&self, now: Instant) -> Option<Instant> {
+        self.rate_limiter.as_ref()?.disabled_until(now)
+    }
+
+    /// Time at which the service state changes by itself and the registration should be updated
+    pub(crate) fn next_deadline(&self) -> Option<Instant> {
+        let now = Instant::now();
+        let access_times_change = match &self.access_windows {
+            Some(access_windows) if self.pauses_outside_access_times() => {
+                Some(now + access_windows.until_change())
+            }
+            _ => None,
+        };
+        let connection_deadlines = self
+            .internal_connections
+            .values()
+            .filter_map(|connection| connection.deadline())
+            .chain(
+                self.redirect_connections
+                    .values()
+                    .filter_map(|connection| connection.deadline()),
+            )
+            .chain(
+                self.proxy_connections
+                    .values()
+                    .map(|connection| connection.deadline()),
+            );
+        self.rate_disabled_until(now)
+            .into_iter()
+            .chain(access_times_change)
+            .chain(connection_deadlines)
+            .min()
+    }
+
+    /// Whether new connections should be accepted; with `instances_action = pause` or
+    /// `access_times_action = pause`, connections are left in the kernel backlog.
+    pub(crate) fn is_accepting(&self) -> bool {
+        if self.rate_disabled_until(Instant::now()).is_some() {
+            return false;
+        }
+        if self.pauses_outside_access_times() && !self.in_access_times() {
+            return false;
+        }
+        !(self.service.instances_action == LimitAction::Pause && self.at_instance_limit())
+    }
+
+    /// Forget a child that was reaped.
+    ///
+    /// Returns `false` if `pid` is not a child of this service.
+    pub(crate) fn child_exited(&mut self, pid: u32, status: ExitStatus) -> bool {
+        if self.child_procs.remove(&pid).is_none() {
+            return false;
+        }
+        info!(
+            "service {:?} child exited with status {}",
+            self.service.name, status
+        );
+        if self.wait_child == Some(pid) {
+            self.wait_child = None;
+        }
+        true
+    }
+
+    /// Send `signal` to all live children
+    pub(crate) fn signal_children(&self, signal: Signal) {
+        for &pid in self.child_procs.keys() {
+            if let Err(err) = kill(Pid::from_raw(pid as i32), signal) {
+                warn!(
+                    "failed to send {} to service {:?} child {}: {}",
+                    signal, self.service.name, pid, err
+                );
+            }
+        }
+    }
+
+    /// SIGKILL and reap all live children.
+    ///
+    /// Returns the number of children that were killed.
+    pub(crate) fn kill_children(&mut self) -> usize {
+        let killed = self.child_procs.len();
+        for (pid, ChildProc { mut child, .. }) in self.child_procs.drain() {
+            warn!(
+                "killing service {:?} child {} that did not exit",
+                self.service.name, pid
+            );
+            if let Err(err) = child.kill() {
+                warn!("failed to kill child {}: {}", pid, err);
+            }
+            match child.wait() {
+                Ok(status) => info!(
                     "service {:?} child exited with status {}",
                     self.service.name, status
                 ),
-                Ok(None) => new_children.push(child),
-                Err(err) => info!(
-                    "failed to wait for service {:?} child: {}",
-                    self.service.name, err
-                ),
+                Err(err) => warn!("failed to wait for child {}: {}", pid, err),
+            }
+        }
+        self.wait_child = None;
+        killed
+    }
+
+    /// Stop accepting: deregister and close the listener
+    pub(crate) fn close_listener(&mut self, registry: &Registry) -> crate::Result<()> {
+        if let Some(mut listener) = self.listener.take() {
+            if self.registered {
+                registry.deregister(&mut listener).with_message(format!(
+                    "failed to deregister service {:?} with mio",
+                    self.service.name
+                ))?;
+                self.registered = false;
             }
         }
-        self.child_procs = new_children;
+        Ok(())
+    }
+
+    /// Move the listener registration to a new token
+    pub(crate) fn set_token(&mut self, registry: &Registry, token: Token) -> crate::Result<()> {
+        if let (Some(listener), true) = (&mut self.listener, self.registered) {
+            registry
+                .reregister(listener, token, Interest::READABLE)
+                .with_message(format!(
+                    "failed to re-register service {:?} with mio",
+                    self.service.name
+                ))?;
+        }
+        Ok(())
+    }
+
+    /// Whether the listener should be polled
+    fn wants_events(&self) -> bool {
+        self.listener.is_some() && !self.is_waiting() && self.is_accepting()
+    }
+
+    /// Register or deregister the listener with mio to match the service state, and close the
+    /// connections that timed out
+    pub(crate) fn update_registration(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+    ) -> crate::Result<()> {
+        self.expire_connections(registry, Instant::now());
+        let wants_events = self.wants_events();
+        let listener = match &mut self.listener {
+            Some(listener) => listener,
+            None => return Ok(()),
+        };
+        if wants_events && !self.registered {
+            // A "wait" child may have left the socket blocking
+            set_fd_nonblocking(listener.as_raw_fd(), true).with_message(format!(
+                "failed to make service {:?} socket non-blocking",
+                self.service.name
+            ))?;
+            registry
+                .register(listener, token, Interest::READABLE)
+                .with_message(format!(
+                    "failed to register service {:?} with mio",
+                    self.service.name
+                ))?;
+        } else if !wants_events && self.registered {
+            registry.deregister(listener).with_message(format!(
+                "failed to deregister service {:?} with mio",
+                self.service.name
+            ))?;
+        }
+        self.registered = wants_events;
+        Ok(())
     }
 }
+
+fn access_windows(service: &Service) -> Option<AccessWindows> {
+    service
+        .access_times
+        .as_ref()
+        .map(|access_times| AccessWindows::new(access_times, Box::new(LocalClock)))
+}
+
+/// `redirect` target, for logging
+fn redirect_target(service: &Service) -> String {
+    service
+        .redirect
+        .as_ref()
+        .map(|redirect| redirect.to_string())
+        .unwrap_or_default()
+}
+
+fn rate_limiter(service: &Service) -> Option<RateLimiter> {
+    service.cps.map(|cps| RateLimiter::new(cps, Instant::now()))
+}
@@ -0,0 +1,98 @@
+use std::{
+    convert::TryFrom,
+    io,
+    os::unix::{io::AsRawFd, process::ExitStatusExt},
+    process::ExitStatus,
+};
+
+use log::warn;
+use mio::{event::Source, unix::SourceFd, Interest, Registry, Token};
+use nix::sys::{
+    signal::{SigSet, Signal},
+    signalfd::{SfdFlags, SignalFd},
+};
+
+use crate::error::StdIoErrorExt;
+
+/// Signals that are delivered through the event loop instead of asynchronous handlers
+const HANDLED_SIGNALS: &[Signal] = &[
+    Signal::SIGCHLD,
+    Signal::SIGTERM,
+    Signal::SIGINT,
+    Signal::SIGHUP,
+];
+
+/// signalfd for the signals handled by yinetd
+pub(crate) struct SignalSource {
+    signal_fd: SignalFd,
+}
+
+impl SignalSource {
+    /// Block the handled signals and create a signalfd to receive them.
+    ///
+    /// Must be called before any children are spawned.
+    pub(crate) fn new() -> crate::Result<Self> {
+        let mut mask = SigSet::empty();
+        for &signal in HANDLED_SIGNALS {
+            mask.add(signal);
+        }
+        mask.thread_block()
+            .with_message("failed to block signals")?;
+        let signal_fd = SignalFd::with_flags(&mask, SfdFlags::SFD_NONBLOCK | SfdFlags::SFD_CLOEXEC)
+            .with_message("failed to create signalfd")?;
+        Ok(Self { signal_fd })
+    }
+
+    /// Read all pending signals
+    pub(crate) fn read_pending(&mut self) -> crate::Result<Vec<Signal>> {
+        let mut signals = Vec::new();
+        while let Some(siginfo) = self
+            .signal_fd
+            .read_signal()
+            .with_message("failed to read signalfd")?
+        {
+            match Signal::try_from(siginfo.ssi_signo as i32) {
+                Ok(signal) => signals.push(signal),
+                Err(_) => warn!("Read unknown signal {}", siginfo.ssi_signo),
+            }
+        }
+        Ok(signals)
+    }
+}
+
+impl Source for SignalSource {
+    fn register(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        SourceFd(&self.signal_fd.as_raw_fd()).register(registry, token, interests)
+    }
+
+    fn reregister(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        SourceFd(&self.signal_fd.as_raw_fd()).reregister(registry, token, interests)
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        SourceFd(&self.signal_fd.as_raw_fd()).deregister(registry)
+    }
+}
+
+/// Reap one exited child without blocking.
+///
+/// Returns `None` when no more children have exited.
+pub(crate) fn try_wait_any() -> Option<(u32, ExitStatus)> {
+    let mut status: libc::c_int = 0;
+    let pid = unsafe { libc::waitpid(-1, &mut status, libc::WNOHANG) };
+    if pid <= 0 {
+        // 0: children exist, but none have exited; -1 (ECHILD): no children
+        return None;
+    }
+    Some((pid as u32, ExitStatus::from_raw(status)))
+}
@@ -0,0 +1,177 @@
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::{self, Read},
+    mem,
+    os::unix::io::{AsRawFd, FromRawFd},
+    process::Stdio,
+};
+
+use log::{info, warn};
+use mio::{event::Source, unix::SourceFd, Interest, Registry, Token};
+use nix::{
+    fcntl::OFlag,
+    unistd::{dup, pipe2},
+};
+
+use super::ConnectionToken;
+use crate::{config::StderrMode, error::StdIoErrorExt, service::Service};
+
+/// Longer stderr lines are logged in pieces
+const MAX_LINE_LEN: usize = 4096;
+
+/// Stderr of a `service` server spawned for `connection`; `None` to inherit ours.
+///
+/// In `log` mode, also returns the read end of the stderr pipe, which should be added to the
+/// [StderrLogs] once the server is spawned.
+pub(crate) fn server_stderr<C: AsRawFd>(
+    service: &Service,
+    connection: &C,
+    connection_id: u64,
+) -> crate::Result<(Option<Stdio>, Option<StderrLog>)> {
+    let stdio = match &service.stderr {
+        None => return Ok((None, None)),
+        Some(StderrMode::Socket) => dup_stdio(connection)?,
+        Some(StderrMode::File(path)) => {
+            // Opened before any chroot, with our privileges
+            let file = OpenOptions::new()
+                .append(true)
+                .create(true)
+                .open(path)
+                .with_message(format!(
+                    "failed to open stderr file {:?} of service {:?}",
+                    path, service.name
+                ))?;
+            Stdio::from(file)
+        }
+        Some(StderrMode::Null) => Stdio::null(),
+        Some(StderrMode::Log) => {
+            let (read_fd, write_fd) =
+                pipe2(OFlag::O_CLOEXEC).with_message("failed to create stderr pipe")?;
+            // Safe: the pipe was just created, so these are the only owners of its ends
+            let (pipe, stdio) =
+                unsafe { (File::from_raw_fd(read_fd), Stdio::from_raw_fd(write_fd)) };
+            // Only our end is non-blocking
+            super::set_fd_nonblocking(read_fd, true)
+                .with_message("failed to make stderr pipe non-blocking")?;
+            let log = StderrLog {
+                pipe,
+                service: service.name.clone(),
+                connection_id,
+                partial: Vec::new(),
+            };
+            return Ok((Some(stdio), Some(log)));
+        }
+    };
+    Ok((Some(stdio), None))
+}
+
+/// Duplicate `connection` for use as the stderr of a child
+pub(crate) fn dup_stdio<C: AsRawFd>(connection: &C) -> crate::Result<Stdio> {
+    let fd = dup(connection.as_raw_fd()).with_message("failed to dup connection for stderr")?;
+    // Safe: the fd was just duplicated, so the Stdio is its only owner
+    Ok(unsafe { Stdio::from_raw_fd(fd) })
+}
+
+/// Read end of the stderr pipe of a server in `log` mode
+#[derive(Debug)]
+pub(crate) struct StderrLog {
+    pipe: File,
+    service: String,
+    connection_id: u64,
+    /// Start of a line that was not terminated yet
+    partial: Vec<u8>,
+}
+
+impl StderrLog {
+    /// Log the lines that can be read without blocking.
+    ///
+    /// Returns whether the pipe is still open.
+    fn read_lines(&mut self) -> bool {
+        let mut buf = [0; MAX_LINE_LEN];
+        loop {
+            match self.pipe.read(&mut buf) {
+                Ok(0) => break,
+                Ok(len) => {
+                    self.partial.extend_from_slice(&buf[..len]);
+                    self.log_lines();
+                }
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => return true,
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => {
+                    warn!(
+                        "Failed to read stderr of service {:?} connection {}: {}",
+                        self.service, self.connection_id, err
+                    );
+                    break;
+                }
+            }
+        }
+        if !self.partial.is_empty() {
+            let line = mem::take(&mut self.partial);
+            self.log_line(&line);
+        }
+        false
+    }
+
+    /// Log the complete lines of `partial`
+    fn log_lines(&mut self) {
+        while let Some(end) = self.partial.iter().position(|&byte| byte == b'\n') {
+            let line: Vec<u8> = self.partial.drain(..=end).collect();
+            self.log_line(&line[..end]);
+        }
+        while self.partial.len() >= MAX_LINE_LEN {
+            let line: Vec<u8> = self.partial.drain(..MAX_LINE_LEN).collect();
+            self.log_line(&line);
+        }
+    }
+
+    fn log_line(&self, line: &[u8]) {
+        let line = String::from_utf8_lossy(line);
+        info!(
+            "Service {:?} connection {}: {}",
+            self.service,
+            self.connection_id,
+            line.trim_end_matches('\r')
+        );
+    }
+}
+
+/// Stderr pipes of servers in `log` mode, polled by the event loop until the servers close them
+#[derive(Debug, Default)]
+pub(crate) struct StderrLogs {
+    logs: HashMap<Token, (ConnectionToken, StderrLog)>,
+}
+
+impl StderrLogs {
+    /// Poll the pipe of a spawned server. If that fails, its stderr is discarded.
+    pub(crate) fn add(&mut self, registry: &Registry, log: StderrLog) {
+        let token = ConnectionToken::new();
+        if let Err(err) =
+            SourceFd(&log.pipe.as_raw_fd()).register(registry, token.token(), Interest::READABLE)
+        {
+            warn!(
+                "Failed to register stderr pipe of service {:?} connection {}, discarding its \
+                 stderr: {}",
+                log.service, log.connection_id, err
+            );
+            return;
+        }
+        self.logs.insert(token.token(), (token, log));
+    }
+
+    /// Log the lines of the pipe with `token`; returns false if `token` is not a stderr pipe
+    pub(crate) fn handle_event(&mut self, registry: &Registry, token: Token) -> bool {
+        let log = match self.logs.get_mut(&token) {
+            Some((_, log)) => log,
+            None => return false,
+        };
+        if !log.read_lines() {
+            if let Some((_, log)) = self.logs.remove(&token) {
+                // Closing the pipe would deregister it too, but mio expects an explicit deregister
+                let _ = SourceFd(&log.pipe.as_raw_fd()).deregister(registry);
+            }
+        }
+        true
+    }
+}
@@ -0,0 +1,718 @@
+//! TCP wrappers access control: tcpd `hosts.allow`/`hosts.deny` rule files, see hosts_access(5)
+//! and hosts_options(5).
+//!
+//! The rule files, and the pattern files they refer to, are read when the service starts and on
+//! every reload, instead of for every connection like tcpd does. Host names are only known from the
+//! hosts file, so `PARANOID` never matches and user names (`user@host`) are always unknown.
+
+use std::{
+    collections::HashMap,
+    fs, io,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
+};
+
+use log::warn;
+
+use super::access::{canonical_addr, Hosts, Net};
+use crate::{config::max_prefix_len, service::Service};
+
+pub(crate) const HOSTS_ALLOW_PATH: &str = "/etc/hosts.allow";
+pub(crate) const HOSTS_DENY_PATH: &str = "/etc/hosts.deny";
+
+/// Characters that are kept in `%` expansions of shell commands; others are replaced with `_`
+const SHELL_SAFE_CHARS: &str = "!@%-_=+:,./";
+
+/// Host patterns of the pattern files that rules refer to, by path
+type PatternFiles = HashMap<PathBuf, Vec<String>>;
+
+/// Connection to check against the rules
+struct Request<'a> {
+    /// Daemon name: the service name
+    daemon: &'a str,
+    client: SocketAddr,
+    server: Option<SocketAddr>,
+    /// Used to resolve host names
+    hosts: &'a Hosts,
+    pattern_files: &'a PatternFiles,
+}
+
+/// Address and name of one end of the connection
+struct Host<'a> {
+    addr: IpAddr,
+    name: Option<&'a str>,
+}
+
+impl<'a> Request<'a> {
+    fn host(&self, addr: IpAddr) -> Host<'a> {
+        let addr = canonical_addr(addr);
+        Host {
+            addr,
+            name: self.hosts.name_of(addr),
+        }
+    }
+
+    fn client_host(&self) -> Host<'a> {
+        self.host(self.client.ip())
+    }
+
+    fn server_host(&self) -> Option<Host<'a>> {
+        self.server.map(|server| self.host(server.ip()))
+    }
+}
+
+/// Outcome of the rule files for a connection
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct Verdict {
+    pub(crate) allow: bool,
+    /// Expanded `spawn` shell commands, to run in the background
+    pub(crate) spawn: Vec<String>,
+    /// Expanded `twist` shell command, that serves the connection instead of the server
+    pub(crate) twist: Option<String>,
+}
+
+/// Rule of a rule file: daemon list, client list and options
+#[derive(Debug)]
+struct Rule {
+    /// Number of the first line of the rule, for warnings
+    line_num: usize,
+    fields: Vec<String>,
+}
+
+/// Parsed rule file
+#[derive(Debug)]
+struct RuleFile {
+    path: PathBuf,
+    rules: Vec<Rule>,
+}
+
+impl RuleFile {
+    /// Read the rules of `path`; a missing file has no rules
+    fn load(path: PathBuf) -> Self {
+        let rules = match fs::read_to_string(&path) {
+            Ok(contents) => parse_rules(&contents, &path),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(err) => {
+                warn!("Failed to read tcp wrappers file {:?}: {}", path, err);
+                Vec::new()
+            }
+        };
+        Self { path, rules }
+    }
+}
+
+/// Rules of a service
+#[derive(Debug)]
+pub(crate) struct TcpWrappers {
+    allow: RuleFile,
+    deny: RuleFile,
+    pattern_files: PatternFiles,
+}
+
+impl TcpWrappers {
+    /// Read the rule files and the pattern files they refer to
+    pub(crate) fn load(allow_path: PathBuf, deny_path: PathBuf) -> Self {
+        let allow = RuleFile::load(allow_path);
+        let deny = RuleFile::load(deny_path);
+        let mut pattern_files = PatternFiles::new();
+        for rule in allow.rules.iter().chain(deny.rules.iter()) {
+            for list in rule.fields[..2].iter() {
+                load_pattern_files(list, &mut pattern_files);
+            }
+        }
+        Self {
+            allow,
+            deny,
+            pattern_files,
+        }
+    }
+
+    /// Rules of `service`, if it has `tcp_wrappers` enabled
+    pub(crate) fn for_service(service: &Service) -> Option<Self> {
+        if !service.tcp_wrappers.unwrap_or_default().0 {
+            return None;
+        }
+        let path = |path: &Option<PathBuf>, default: &str| {
+            path.clone().unwrap_or_else(|| PathBuf::from(default))
+        };
+        Some(Self::load(
+            path(&service.hosts_allow, HOSTS_ALLOW_PATH),
+            path(&service.hosts_deny, HOSTS_DENY_PATH),
+        ))
+    }
+
+    /// Access is granted by the first matching rule of `hosts.allow`, otherwise denied by the
+    /// first matching rule of `hosts.deny`, otherwise granted. Missing files have no rules.
+    pub(crate) fn check(
+        &self,
+        daemon: &str,
+        client: SocketAddr,
+        server: Option<SocketAddr>,
+        hosts: &Hosts,
+    ) -> Verdict {
+        let request = Request {
+            daemon,
+            client,
+            server,
+            hosts,
+            pattern_files: &self.pattern_files,
+        };
+        for (rule_file, allow) in [(&self.allow, true), (&self.deny, false)].iter() {
+            if let Some(verdict) = check_rules(&rule_file.rules, &rule_file.path, *allow, &request)
+            {
+                return verdict;
+            }
+        }
+        Verdict {
+            allow: true,
+            ..Verdict::default()
+        }
+    }
+}
+
+/// Parse the rules of a rule file, skipping invalid ones
+fn parse_rules(contents: &str, path: &Path) -> Vec<Rule> {
+    logical_lines(contents)
+        .into_iter()
+        .filter_map(|(line_num, line)| {
+            let fields = split_fields(&line);
+            if fields.len() < 2 {
+                warn!("{}:{}: missing \":\" separator", path.display(), line_num);
+                return None;
+            }
+            Some(Rule { line_num, fields })
+        })
+        .collect()
+}
+
+/// Read the pattern files named in a daemon or client list
+fn load_pattern_files(list: &str, pattern_files: &mut PatternFiles) {
+    for pattern in list_patterns(list) {
+        // The host part of `daemon@host` and `user@host` patterns may name a file too
+        let host = pattern.rsplit('@').next().unwrap_or(pattern);
+        if host.starts_with('/') && !pattern_files.contains_key(Path::new(host)) {
+            let mut patterns = Vec::new();
+            read_pattern_file(Path::new(host), &mut Vec::new(), &mut patterns);
+            pattern_files.insert(PathBuf::from(host), patterns);
+        }
+    }
+}
+
+/// Append the host patterns of a pattern file to `patterns`, replacing the pattern files it
+/// names with their patterns. `reading` holds the files being read, to skip cyclic references.
+fn read_pattern_file(path: &Path, reading: &mut Vec<PathBuf>, patterns: &mut Vec<String>) {
+    if reading.iter().any(|reading| reading == path) {
+        return;
+    }
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(err) => {
+            warn!(
+                "Failed to read tcp wrappers pattern file {:?}: {}",
+                path, err
+            );
+            return;
+        }
+    };
+    reading.push(path.to_path_buf());
+    for pattern in contents
+        .lines()
+        .filter(|line| !line.trim_start().starts_with('#'))
+        .flat_map(list_patterns)
+    {
+        if pattern.starts_with('/') {
+            read_pattern_file(Path::new(pattern), reading, patterns);
+        } else {
+            patterns.push(pattern.to_string());
+        }
+    }
+    reading.pop();
+}
+
+/// Verdict of the first of `rules` that matches `request`
+fn check_rules(rules: &[Rule], path: &Path, allow: bool, request: &Request) -> Option<Verdict> {
+    for rule in rules.iter() {
+        let fields = &rule.fields;
+        let daemon_matches = list_match(&fields[0], &|pattern| daemon_match(pattern, request));
+        if !daemon_matches
+            || !list_match(&fields[1], &|pattern| {
+                client_match(pattern, &request.client_host(), request)
+            })
+        {
+            continue;
+        }
+
+        let mut verdict = Verdict {
+            allow,
+            ..Verdict::default()
+        };
+        for option in fields[2..].iter() {
+            if !apply_option(option, &mut verdict, request) {
+                warn!(
+                    "{}:{}: unsupported option {:?} ignored",
+                    path.display(),
+                    rule.line_num,
+                    option
+                );
+            }
+        }
+        return Some(verdict);
+    }
+    None
+}
+
+/// Join backslash-continued lines and skip comments and blank lines.
+///
+/// Yields the number of the first line of each rule.
+fn logical_lines(contents: &str) -> Vec<(usize, String)> {
+    let mut lines = Vec::new();
+    let mut current: Option<(usize, String)> = None;
+    for (idx, line) in contents.lines().enumerate() {
+        let (line_num, mut text) = current.take().unwrap_or((idx + 1, String::new()));
+        if text.is_empty() && line.trim_start().starts_with('#') {
+            continue;
+        }
+        match line.strip_suffix('\\') {
+            Some(continued) => {
+                text.push_str(continued);
+                current = Some((line_num, text));
+            }
+            None => {
+                text.push_str(line);
+                if !text.trim().is_empty() {
+                    lines.push((line_num, text));
+                }
+            }
+        }
+    }
+    lines.extend(current.filter(|(_, text)| !text.trim().is_empty()));
+    lines
+}
+
+/// Split a rule at `:` separators, except inside `[...]` IPv6 addresses or when escaped as `\:`
+fn split_fields(line: &str) -> Vec<String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut in_brackets = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if chars.peek() == Some(&':') => field.push(chars.next().unwrap()),
+            '[' => {
+                in_brackets = true;
+                field.push(c);
+            }
+            ']' => {
+                in_brackets = false;
+                field.push(c);
+            }
+            ':' if !in_brackets => fields.push(std::mem::take(&mut field)),
+            _ => field.push(c),
+        }
+    }
+    fields.push(field);
+    fields
+        .iter()
+        .map(|field| field.trim().to_string())
+        .collect()
+}
+
+/// Patterns of a whitespace/comma separated list
+fn list_patterns(list: &str) -> impl Iterator<Item = &str> {
+    list.split(|c: char| c.is_whitespace() || c == ',')
+        .filter(|pattern| !pattern.is_empty())
+}
+
+/// Match a whitespace/comma separated list of patterns, with `EXCEPT` operators
+fn list_match(list: &str, matches: &dyn Fn(&str) -> bool) -> bool {
+    let patterns: Vec<&str> = list_patterns(list).collect();
+    patterns_match(&patterns, matches)
+}
+
+/// `a EXCEPT b EXCEPT c` means `a EXCEPT (b EXCEPT c)`
+fn patterns_match(patterns: &[&str], matches: &dyn Fn(&str) -> bool) -> bool {
+    match patterns
+        .iter()
+        .position(|pattern| pattern.eq_ignore_ascii_case("EXCEPT"))
+    {
+        Some(pos) => {
+            patterns[..pos].iter().any(|pattern| matches(pattern))
+                && !patterns_match(&patterns[pos + 1..], matches)
+        }
+        None => patterns.iter().any(|pattern| matches(pattern)),
+    }
+}
+
+/// Match a daemon pattern: `name`, `name@host` or `ALL`
+fn daemon_match(pattern: &str, request: &Request) -> bool {
+    match pattern.split_once('@') {
+        Some((daemon, host)) => {
+            string_match(daemon, request.daemon)
+                && request
+                    .server_host()
+                    .is_some_and(|server| host_match(host, &server, request))
+        }
+        None => string_match(pattern, request.daemon),
+    }
+}
+
+/// Match a client pattern: a host pattern, or `user@host`
+fn client_match(pattern: &str, client: &Host, request: &Request) -> bool {
+    match pattern.find('@') {
+        // User names are never known
+        Some(pos) if pos > 0 => {
+            let user = &pattern[..pos];
+            (user.eq_ignore_ascii_case("ALL") || user.eq_ignore_ascii_case("UNKNOWN"))
+                && host_match(&pattern[pos + 1..], client, request)
+        }
+        _ => host_match(pattern, client, request),
+    }
+}
+
+fn host_match(pattern: &str, host: &Host, request: &Request) -> bool {
+    if pattern.starts_with('@') {
+        // NIS netgroups are not supported
+        false
+    } else if pattern.starts_with('/') {
+        // Any pattern listed in the file
+        request
+            .pattern_files
+            .get(Path::new(pattern))
+            .is_some_and(|patterns| {
+                patterns
+                    .iter()
+                    .any(|pattern| host_match(pattern, host, request))
+            })
+    } else if pattern.eq_ignore_ascii_case("ALL") {
+        true
+    } else if pattern.eq_ignore_ascii_case("KNOWN") {
+        host.name.is_some()
+    } else if pattern.eq_ignore_ascii_case("UNKNOWN") {
+        host.name.is_none()
+    } else if pattern.eq_ignore_ascii_case("LOCAL") {
+        host.name.is_some_and(|name| !name.contains('.'))
+    } else if pattern.eq_ignore_ascii_case("PARANOID") {
+        false
+    } else if let Some(net) = parse_net(pattern) {
+        net.contains(host.addr)
+    } else {
+        host.name.is_some_and(|name| string_match(pattern, name))
+            || string_match(pattern, &host.addr.to_string())
+            || request.hosts.lookup(pattern).contains(&host.addr)
+    }
+}
+
+/// Parse `n.n.n.n/m.m.m.m`, `n.n.n.n/len`, `[v6]/len` or `[v6]` network patterns
+fn parse_net(pattern: &str) -> Option<Net> {
+    let (addr, mask) = match pattern.split_once('/') {
+        Some((addr, mask)) => (addr, Some(mask)),
+        None => (pattern, None),
+    };
+    let bracketed = addr
+        .strip_prefix('[')
+        .and_then(|addr| addr.strip_suffix(']'));
+    if mask.is_none() && bracketed.is_none() {
+        // Plain addresses are matched as strings
+        return None;
+    }
+    let addr: IpAddr = bracketed.unwrap_or(addr).parse().ok()?;
+
+    let prefix_len = match mask {
+        None => max_prefix_len(addr),
+        Some(mask) => match mask.parse::<u8>() {
+            Ok(prefix_len) if prefix_len <= max_prefix_len(addr) => prefix_len,
+            Ok(_) => return None,
+            Err(_) => {
+                let mask = u32::from(mask.parse::<Ipv4Addr>().ok()?);
+                if !addr.is_ipv4() || mask.leading_ones() != mask.count_ones() {
+                    return None;
+                }
+                mask.count_ones() as u8
+            }
+        },
+    };
+    Some(Net::new(addr, prefix_len))
+}
+
+/// Match a name or address string: `ALL`, `.domain` suffix, `net.` prefix, `*`/`?` wildcards or
+/// case-insensitive equality
+fn string_match(pattern: &str, string: &str) -> bool {
+    let pattern = pattern.to_lowercase();
+    let string = string.to_lowercase();
+    if pattern == "all" {
+        true
+    } else if pattern.starts_with('.') {
+        string.ends_with(&pattern)
+    } else if pattern.ends_with('.') {
+        string.starts_with(&pattern)
+    } else if pattern.contains(['*', '?']) {
+        wildcard_match(pattern.as_bytes(), string.as_bytes())
+    } else {
+        pattern == string
+    }
+}
+
+fn wildcard_match(pattern: &[u8], string: &[u8]) -> bool {
+    match (pattern.first(), string.first()) {
+        (None, None) => true,
+        (Some(b'*'), _) => {
+            wildcard_match(&pattern[1..], string)
+                || (!string.is_empty() && wildcard_match(pattern, &string[1..]))
+        }
+        (Some(b'?'), Some(_)) => wildcard_match(&pattern[1..], &string[1..]),
+        (Some(p), Some(s)) if p == s => wildcard_match(&pattern[1..], &string[1..]),
+        _ => false,
+    }
+}
+
+/// Apply a rule option; returns false for unsupported options
+fn apply_option(option: &str, verdict: &mut Verdict, request: &Request) -> bool {
+    let keyword_end = option
+        .find(|c: char| c.is_whitespace() || c == '=')
+        .unwrap_or(option.len());
+    let keyword = option[..keyword_end].to_lowercase();
+    let value = option[keyword_end..].trim_start();
+    let value = value.strip_prefix('=').unwrap_or(value).trim();
+    match keyword.as_str() {
+        "allow" => verdict.allow = true,
+        "deny" => verdict.allow = false,
+        "spawn" => verdict.spawn.push(expand(value, request)),
+        "twist" => verdict.twist = Some(expand(value, request)),
+        _ => return false,
+    }
+    true
+}
+
+/// Expand `%` sequences of a shell command
+fn expand(command: &str, request: &Request) -> String {
+    let client = request.client_host();
+    let server = request.server_host();
+    let host_or_addr = |host: &Host| {
+        host.name
+            .map(str::to_string)
+            .unwrap_or_else(|| host.addr.to_string())
+    };
+    let unknown = || "unknown".to_string();
+
+    let mut expanded = String::new();
+    let mut chars = command.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            expanded.push(c);
+            continue;
+        }
+        let value = match chars.next() {
+            Some('a') => client.addr.to_string(),
+            Some('A') => server
+                .as_ref()
+                .map_or_else(unknown, |server| server.addr.to_string()),
+            Some('c') | Some('h') => host_or_addr(&client),
+            Some('d') => request.daemon.to_string(),
+            Some('H') => server.as_ref().map_or_else(unknown, host_or_addr),
+            Some('n') => client.name.map_or_else(unknown, str::to_string),
+            Some('N') => server
+                .as_ref()
+                .and_then(|server| server.name)
+                .map_or_else(unknown, str::to_string),
+            Some('p') => std::process::id().to_string(),
+            Some('s') => match &server {
+                Some(server) => format!("{}@{}", request.daemon, host_or_addr(server)),
+                None => request.daemon.to_string(),
+            },
+            Some('u') => unknown(),
+            Some('%') => {
+                expanded.push('%');
+                continue;
+            }
+            Some(other) => {
+                warn!("Unknown tcp wrappers expansion %{} in {:?}", other, command);
+                continue;
+            }
+            None => break,
+        };
+        expanded.extend(value.chars().map(|c| {
+            if c.is_ascii_alphanumeric() || SHELL_SAFE_CHARS.contains(c) {
+                c
+            } else {
+                '_'
+            }
+        }));
+    }
+    expanded
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const HOSTS: &str = "
+127.0.0.1   localhost
+10.1.2.3    build.example.com build
+10.1.2.4    web.example.com
+192.0.2.1   yinetd-host
+";
+
+    fn rule_file(contents: &str) -> RuleFile {
+        let path = PathBuf::from("test");
+        RuleFile {
+            rules: parse_rules(contents, &path),
+            path,
+        }
+    }
+
+    fn check(allow: &str, deny: &str, daemon: &str, client: &str) -> Verdict {
+        let tcp_wrappers = TcpWrappers {
+            allow: rule_file(allow),
+            deny: rule_file(deny),
+            pattern_files: PatternFiles::new(),
+        };
+        tcp_wrappers.check(
+            daemon,
+            SocketAddr::new(client.parse().unwrap(), 40000),
+            Some("192.0.2.1:23".parse().unwrap()),
+            &Hosts::parse(HOSTS),
+        )
+    }
+
+    fn allowed(allow: &str, deny: &str, daemon: &str, client: &str) -> bool {
+        check(allow, deny, daemon, client).allow
+    }
+
+    #[test]
+    fn allow_then_deny() {
+        let allow = "telnet: 10.1.2.3\n";
+        let deny = "ALL: ALL\n";
+        assert!(allowed(allow, deny, "telnet", "10.1.2.3"));
+        assert!(!allowed(allow, deny, "telnet", "10.1.2.4"));
+        assert!(!allowed(allow, deny, "ftp", "10.1.2.3"));
+        assert!(allowed("", "", "ftp", "10.1.2.3"));
+    }
+
+    #[test]
+    fn lists_and_except() {
+        let deny = "ALL EXCEPT ftp, echo: ALL EXCEPT 10. EXCEPT 10.1.2.4\n";
+        assert!(!allowed("", deny, "telnet", "192.0.2.7"));
+        assert!(allowed("", deny, "telnet", "10.1.2.3"));
+        assert!(!allowed("", deny, "telnet", "10.1.2.4"));
+        assert!(allowed("", deny, "ftp", "192.0.2.7"));
+        assert!(allowed("", deny, "echo", "192.0.2.7"));
+    }
+
+    #[test]
+    fn host_patterns() {
+        let matches = |pattern: &str, client: &str| {
+            !allowed("", &format!("ALL: {}", pattern), "telnet", client)
+        };
+        assert!(matches(".example.com", "10.1.2.4"));
+        assert!(!matches(".example.com", "127.0.0.1"));
+        assert!(matches("192.0.", "192.0.2.7"));
+        assert!(!matches("192.0.", "192.10.2.7"));
+        assert!(matches("10.0.0.0/255.0.0.0", "10.200.0.1"));
+        assert!(matches("10.0.0.0/8", "10.200.0.1"));
+        assert!(!matches("10.0.0.0/255.0.0.0", "11.0.0.1"));
+        assert!(matches("[2001:db8::]/32", "2001:db8::1"));
+        assert!(matches("[::1]", "::1"));
+        assert!(!matches("[::1]", "::2"));
+        assert!(matches("10.1.2.3", "::ffff:10.1.2.3"));
+        assert!(matches("build", "10.1.2.3"));
+        assert!(matches("*.example.com", "10.1.2.3"));
+        assert!(matches("10.1.2.?", "10.1.2.4"));
+        assert!(matches("KNOWN", "10.1.2.4"));
+        assert!(matches("UNKNOWN", "10.9.9.9"));
+        assert!(matches("LOCAL", "127.0.0.1"));
+        assert!(!matches("LOCAL", "10.1.2.3"));
+        assert!(!matches("PARANOID", "10.9.9.9"));
+        assert!(matches("ALL@10.1.2.3", "10.1.2.3"));
+        assert!(!matches("root@10.1.2.3", "10.1.2.3"));
+        assert!(!matches("@netgroup", "10.1.2.3"));
+    }
+
+    #[test]
+    fn daemon_at_host() {
+        assert!(!allowed("", "telnet@192.0.2.1: ALL", "telnet", "10.1.2.3"));
+        assert!(!allowed(
+            "",
+            "telnet@yinetd-host: ALL",
+            "telnet",
+            "10.1.2.3"
+        ));
+        assert!(allowed("", "telnet@192.0.2.2: ALL", "telnet", "10.1.2.3"));
+    }
+
+    #[test]
+    fn options() {
+        let allow = "\
+# comment
+telnet: ALL EXCEPT 10.1.2.4: spawn echo %d from %a \\
+    to %A: deny
+ftp: ALL: twist /bin/echo \"no ftp for %h\\: sorry\"
+echo: ALL: severity auth.info
+";
+        assert_eq!(
+            check(allow, "", "telnet", "10.1.2.3"),
+            Verdict {
+                allow: false,
+                spawn: vec!["echo telnet from 10.1.2.3     to 192.0.2.1".to_string()],
+                twist: None,
+            }
+        );
+        assert!(allowed(allow, "", "telnet", "10.1.2.4"));
+        assert_eq!(
+            check(allow, "", "ftp", "10.1.2.3"),
+            Verdict {
+                allow: true,
+                spawn: vec![],
+                twist: Some("/bin/echo \"no ftp for build.example.com: sorry\"".to_string()),
+            }
+        );
+        assert!(allowed(allow, "ALL: ALL", "echo", "10.1.2.3"));
+    }
+
+    #[test]
+    fn expansion_is_shell_safe() {
+        let hosts = Hosts::parse("10.1.2.3 evil;rm\n");
+        let request = Request {
+            daemon: "telnet",
+            client: "10.1.2.3:40000".parse().unwrap(),
+            server: None,
+            hosts: &hosts,
+            pattern_files: &PatternFiles::new(),
+        };
+        assert_eq!(expand("echo %h %A %%", &request), "echo evil_rm unknown %");
+    }
+
+    #[test]
+    fn load_files() {
+        let dir = std::env::temp_dir().join(format!("yinetd-tcp-wrappers-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let trusted = dir.join("trusted");
+        let nested = dir.join("nested");
+        fs::write(
+            &trusted,
+            format!("# build hosts\n10.1.2.3, {}\n", nested.display()),
+        )
+        .unwrap();
+        fs::write(&nested, format!("10.1.2.4 {}\n", trusted.display())).unwrap();
+        let deny = dir.join("hosts.deny");
+        fs::write(&deny, format!("ALL: ALL EXCEPT {}\n", trusted.display())).unwrap();
+
+        let tcp_wrappers = TcpWrappers::load(dir.join("missing"), deny);
+        assert!(tcp_wrappers.allow.rules.is_empty());
+        // Nested pattern files are expanded, and cyclic references skipped
+        assert_eq!(
+            tcp_wrappers.pattern_files[&trusted],
+            ["10.1.2.3", "10.1.2.4"]
+        );
+        // The files are not read again
+        fs::remove_dir_all(&dir).unwrap();
+
+        let hosts = Hosts::parse(HOSTS);
+        let allowed = |client: &str| {
+            let client = SocketAddr::new(client.parse().unwrap(), 40000);
+            tcp_wrappers.check("telnet", client, None, &hosts).allow
+        };
+        assert!(allowed("10.1.2.3"));
+        assert!(allowed("10.1.2.4"));
+        assert!(!allowed("10.1.2.5"));
+    }
+}
@@ -0,0 +1,141 @@
+use std::{
+    fs, io,
+    os::unix::{fs::FileTypeExt, fs::MetadataExt},
+    path::{Path, PathBuf},
+};
+
+use log::{debug, warn};
+use mio::net::{UnixDatagram, UnixListener};
+
+use crate::{config::UnixAddr, error::StdIoErrorExt};
+
+/// Filesystem entry of a bound Unix domain socket. The entry is removed on drop if it still
+/// refers to the socket that was bound.
+pub(crate) struct SocketPath {
+    path: PathBuf,
+    dev: u64,
+    ino: u64,
+}
+
+impl SocketPath {
+    fn new(path: &Path) -> io::Result<Self> {
+        let metadata = fs::symlink_metadata(path)?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            dev: metadata.dev(),
+            ino: metadata.ino(),
+        })
+    }
+}
+
+impl Drop for SocketPath {
+    fn drop(&mut self) {
+        match fs::symlink_metadata(&self.path) {
+            Ok(metadata) if metadata.dev() == self.dev && metadata.ino() == self.ino => {
+                debug!("Removing socket {:?}", self.path);
+                if let Err(err) = fs::remove_file(&self.path) {
+                    warn!("Failed to remove socket {:?}: {}", self.path, err);
+                }
+            }
+            Ok(_) => debug!("Socket {:?} was replaced, not removing", self.path),
+            Err(err) => debug!("Socket {:?} already gone: {}", self.path, err),
+        }
+    }
+}
+
+/// Remove a socket file left behind by a previous run.
+///
+/// Only sockets that nobody is listening on are removed; other files are never touched.
+fn remove_stale_socket(path: &Path, datagram: bool) -> io::Result<()> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err),
+    };
+    if !metadata.file_type().is_socket() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            "path exists and is not a socket",
+        ));
+    }
+
+    let connect_result = if datagram {
+        std::os::unix::net::UnixDatagram::unbound().and_then(|socket| socket.connect(path))
+    } else {
+        std::os::unix::net::UnixStream::connect(path).map(|_| ())
+    };
+    match connect_result {
+        Ok(()) => Err(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            "socket is in use by another process",
+        )),
+        Err(err) if err.kind() == io::ErrorKind::ConnectionRefused => {
+            debug!("Removing stale socket {:?}", path);
+            fs::remove_file(path)
+        }
+        Err(err) => Err(err),
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn abstract_addr(name: &str) -> io::Result<std::os::unix::net::SocketAddr> {
+    #[cfg(target_os = "android")]
+    use std::os::android::net::SocketAddrExt;
+    #[cfg(target_os = "linux")]
+    use std::os::linux::net::SocketAddrExt;
+
+    std::os::unix::net::SocketAddr::from_abstract_name(name)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn abstract_addr(_name: &str) -> io::Result<std::os::unix::net::SocketAddr> {
+    Err(io::Error::other(
+        "abstract sockets are only supported on Linux",
+    ))
+}
+
+pub(crate) fn bind_listener(addr: &UnixAddr) -> crate::Result<(UnixListener, Option<SocketPath>)> {
+    let bind_message = || format!("failed to bind to {}", addr);
+    match addr {
+        UnixAddr::Path(path) => {
+            remove_stale_socket(path, false).with_message(bind_message())?;
+            let listener = UnixListener::bind(path).with_message(bind_message())?;
+            let socket_path =
+                SocketPath::new(path).with_message(format!("failed to stat {:?}", path))?;
+            Ok((listener, Some(socket_path)))
+        }
+        UnixAddr::Abstract(name) => {
+            let listener = abstract_addr(name)
+                .and_then(|addr| std::os::unix::net::UnixListener::bind_addr(&addr))
+                .and_then(|listener| {
+                    listener.set_nonblocking(true)?;
+                    Ok(listener)
+                })
+                .with_message(bind_message())?;
+            Ok((UnixListener::from_std(listener), None))
+        }
+    }
+}
+
+pub(crate) fn bind_datagram(addr: &UnixAddr) -> crate::Result<(UnixDatagram, Option<SocketPath>)> {
+    let bind_message = || format!("failed to bind to {}", addr);
+    match addr {
+        UnixAddr::Path(path) => {
+            remove_stale_socket(path, true).with_message(bind_message())?;
+            let socket = UnixDatagram::bind(path).with_message(bind_message())?;
+            let socket_path =
+                SocketPath::new(path).with_message(format!("failed to stat {:?}", path))?;
+            Ok((socket, Some(socket_path)))
+        }
+        UnixAddr::Abstract(name) => {
+            let socket = abstract_addr(name)
+                .and_then(|addr| std::os::unix::net::UnixDatagram::bind_addr(&addr))
+                .and_then(|socket| {
+                    socket.set_nonblocking(true)?;
+                    Ok(socket)
+                })
+                .with_message(bind_message())?;
+            Ok((UnixDatagram::from_std(socket), None))
+        }
+    }
+}
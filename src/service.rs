@@ -1,14 +1,27 @@
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::PathBuf,
+};
 
 use pest::error::Error as PestError;
 use pest::error::ErrorVariant as PestErrorVariant;
 use pest::iterators::Pair;
 
 use crate::{
-    config::{parse::Rule, InetType, ProgArgs, SocketType},
+    config::{
+        parse::Rule, AccessTimes, Account, AddrList, Cps, CpuList, EnvNames, EnvVars, InetType,
+        InternalService, Ioprio, IoprioClass, Limit, LimitAction, Nice, OomScoreAdj, ProgArgs,
+        ProxyProtocol, Redirect, RlimitSize, RlimitTime, ServiceType, SocketType, StderrMode,
+        Umask, UnixAddr, YesNo,
+    },
     Error,
 };
 
+/// Config key of a field: its name without any raw identifier prefix (e.g. `r#type`)
+fn option_key(field: &'static str) -> &'static str {
+    field.trim_start_matches("r#")
+}
+
 /// Define two structs that will hold config:
 /// - Required fields will be stored as T; optional fields in an `Option<T>`
 /// - All fields stored as `Option<T>`
@@ -33,7 +46,7 @@ macro_rules! define_config {
         let name_str = $name_pair.as_str();
         match name_str {
             $(
-                stringify!($field) => {
+                _ if name_str == option_key(stringify!($field)) => {
                     if $self.$field.is_some() {
                         return Err(crate::Error::duplicate_option(name_str, &$name_pair));
                     }
@@ -51,7 +64,10 @@ macro_rules! define_config {
                         message: format!(
                             "Invalid key {:?}. Valid keys: {:?}",
                             name_str,
-                            $struct_name::VALID_KEYS),
+                            $struct_name::VALID_KEYS
+                                .iter()
+                                .map(|key| option_key(key))
+                                .collect::<Vec<_>>()),
                     },
                     $name_pair.as_span(),
                 ).into());
@@ -125,6 +141,8 @@ macro_rules! define_config {
             ];
 
             /// Convert from optioned struct. Required fields must be `Some(_)`.
+            // `pair` is only used for errors about required fields, of which there may be none
+            #[allow(unused_variables)]
             pub fn from_optioned(opt_struct: $opt_struct_name, service_name: &str, pair: &Pair<Rule>) -> crate::Result<Self> {
                 Ok(Self {
                     name: service_name.to_string(),
@@ -132,7 +150,7 @@ macro_rules! define_config {
                         $req_field: opt_struct
                             .$req_field
                             .ok_or_else(|| {
-                                let missing_option = stringify!($req_field);
+                                let missing_option = option_key(stringify!($req_field));
                                 crate::Error::missing_required_option(missing_option, service_name, pair)
                             })?,
                     )*
@@ -176,6 +194,187 @@ macro_rules! define_config {
 }
 
 impl Service {
+    /// Socket type after taking `listen_path` into account: a stream/datagram service with a
+    /// `listen_path` is a Unix domain stream/datagram socket service.
+    pub fn effective_socket_type(&self) -> SocketType {
+        match (self.socket_type, &self.listen_path) {
+            (SocketType::Tcp, Some(_)) => SocketType::Unix,
+            (SocketType::Udp, Some(_)) => SocketType::UnixDgram,
+            (socket_type, _) => socket_type,
+        }
+    }
+
+    /// Whether the bound socket itself is handed to the server ("wait" mode), instead of
+    /// accepted connections
+    pub fn wait_mode(&self) -> bool {
+        self.effective_socket_type().is_datagram() || self.wait == Some(YesNo(true))
+    }
+
+    /// Built-in service of an INTERNAL service, from `server` or the service name
+    pub fn internal_service(&self) -> Option<InternalService> {
+        match self.r#type {
+            Some(ServiceType::Internal) => {
+                self.server.as_deref().unwrap_or(&self.name).parse().ok()
+            }
+            Some(ServiceType::Tcpmux) | Some(ServiceType::TcpmuxPlus) | None => None,
+        }
+    }
+
+    /// Whether the service is selected by name through the tcpmux internal service, and has no
+    /// socket of its own
+    pub fn is_tcpmux(&self) -> bool {
+        matches!(
+            self.r#type,
+            Some(ServiceType::Tcpmux) | Some(ServiceType::TcpmuxPlus)
+        )
+    }
+
+    /// User to run the server as, from `user` or `uid`
+    pub fn run_as_user(&self) -> Option<Account> {
+        self.user.clone().or_else(|| self.uid.map(Account::Id))
+    }
+
+    /// Check that options required for the socket type are present and consistent
+    pub fn check(&self, service_pair: &Pair<Rule>) -> crate::Result<()> {
+        if self.redirect.is_some() {
+            self.check_redirect(service_pair)?;
+        }
+        if self.r#type == Some(ServiceType::Internal) {
+            self.check_internal(service_pair)?;
+        } else if self.server.is_none() && self.redirect.is_none() {
+            return Err(Error::missing_required_option(
+                "server",
+                &self.name,
+                service_pair,
+            ));
+        }
+
+        if self.user.is_some() && self.uid.is_some() {
+            return Err(Error::invalid_service(
+                &self.name,
+                service_pair,
+                "\"user\" and \"uid\" are mutually exclusive",
+            ));
+        }
+
+        if self.effective_socket_type().is_datagram() && self.wait == Some(YesNo(false)) {
+            return Err(Error::invalid_service(
+                &self.name,
+                service_pair,
+                "datagram services only support \"wait = yes\"",
+            ));
+        }
+
+        if self.proxy_protocol.is_some() && (self.wait_mode() || self.is_tcpmux()) {
+            return Err(Error::invalid_service(
+                &self.name,
+                service_pair,
+                "\"proxy_protocol\" requires a stream socket with \"wait = no\"; TCPMUX services \
+                 use the one of the tcpmux service",
+            ));
+        }
+
+        if self.is_tcpmux() {
+            return self.check_tcpmux(service_pair);
+        }
+
+        match self.effective_socket_type() {
+            SocketType::Tcp | SocketType::Udp => {
+                if self.port.is_none() {
+                    return Err(Error::missing_required_option(
+                        "port",
+                        &self.name,
+                        service_pair,
+                    ));
+                }
+            }
+            SocketType::Unix | SocketType::UnixDgram => {
+                if self.listen_path.is_none() {
+                    return Err(Error::missing_required_option(
+                        "listen_path",
+                        &self.name,
+                        service_pair,
+                    ));
+                }
+                if self.port.is_some() {
+                    return Err(Error::invalid_service(
+                        &self.name,
+                        service_pair,
+                        "option \"port\" is not valid for Unix domain sockets",
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn check_internal(&self, service_pair: &Pair<Rule>) -> crate::Result<()> {
+        if self.internal_service().is_none() {
+            return Err(Error::invalid_service(
+                &self.name,
+                service_pair,
+                "INTERNAL services must be named, or have a \"server\", echo, discard, daytime, \
+                 chargen, time or tcpmux",
+            ));
+        }
+        if self.internal_service() == Some(InternalService::Tcpmux)
+            && self.effective_socket_type() != SocketType::Tcp
+        {
+            return Err(Error::invalid_service(
+                &self.name,
+                service_pair,
+                "the tcpmux INTERNAL service only supports \"socket_type = tcp\"",
+            ));
+        }
+        match self.effective_socket_type() {
+            SocketType::UnixDgram => Err(Error::invalid_service(
+                &self.name,
+                service_pair,
+                "INTERNAL services do not support Unix datagram sockets",
+            )),
+            SocketType::Tcp | SocketType::Unix if self.wait == Some(YesNo(true)) => {
+                Err(Error::invalid_service(
+                    &self.name,
+                    service_pair,
+                    "INTERNAL stream services only support \"wait = no\"",
+                ))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Redirect services forward connections instead of spawning a server
+    fn check_redirect(&self, service_pair: &Pair<Rule>) -> crate::Result<()> {
+        if self.server.is_some() || self.r#type == Some(ServiceType::Internal) {
+            return Err(Error::invalid_service(
+                &self.name,
+                service_pair,
+                "\"redirect\" replaces \"server\" and INTERNAL",
+            ));
+        }
+        if self.wait_mode() {
+            return Err(Error::invalid_service(
+                &self.name,
+                service_pair,
+                "redirect services only support stream sockets with \"wait = no\"",
+            ));
+        }
+        Ok(())
+    }
+
+    /// TCPMUX services are reached through the tcpmux internal service instead of their own
+    /// socket
+    fn check_tcpmux(&self, service_pair: &Pair<Rule>) -> crate::Result<()> {
+        if self.effective_socket_type() != SocketType::Tcp || self.wait == Some(YesNo(true)) {
+            return Err(Error::invalid_service(
+                &self.name,
+                service_pair,
+                "TCPMUX services only support \"socket_type = tcp\" and \"wait = no\"",
+            ));
+        }
+        Ok(())
+    }
+
     pub fn socket_addr(&self) -> crate::Result<SocketAddr> {
         let mismatch_err = |addr| {
             Err(Error::InetVersionAddressMismatch {
@@ -207,7 +406,8 @@ impl Service {
             }
         };
 
-        Ok((inet_addr, self.port).into())
+        let port = self.port.expect("inet services are checked to have a port");
+        Ok((inet_addr, port).into())
     }
 }
 
@@ -239,14 +439,9 @@ define_config!(
     #[derive(Debug, Clone, Eq, PartialEq)]
     pub struct Service, ServiceOption;
     required {
-        /// Server binary
-        pub server: String,
-
-        /// TCP/UDP Port
-        pub port: u16,
     }
     optional_with_default {
-        /// Socket type (i.e., TCP vs. UDP)
+        /// Socket type (i.e., TCP vs. UDP vs. Unix domain)
         pub socket_type: SocketType = SocketType::Tcp,
 
         /// Inet (i.e., IPv4 vs. IPv6)
@@ -254,13 +449,161 @@ define_config!(
 
         /// Program arguments
         pub server_args: ProgArgs = ProgArgs::default(),
+
+        /// Maximum number of concurrent children
+        pub instances: Limit = Limit::default(),
+
+        /// What to do with connections over the `instances` limit
+        pub instances_action: LimitAction = LimitAction::Pause,
+
+        /// Maximum number of concurrent children per client address
+        pub per_source: Limit = Limit::default(),
+
+        /// What to do with connections outside the `access_times`
+        /// "wait" services always pause
+        pub access_times_action: LimitAction = LimitAction::Reject,
     }
     optional {
+        /// Server binary; required unless the service is INTERNAL or has a `redirect`
+        /// For INTERNAL services, the built-in service: echo, discard, daytime, chargen, time or
+        /// tcpmux
+        /// Defaults to the service name for INTERNAL services
+        pub server: String,
+
+        /// INTERNAL for services served by yinetd itself, without spawning a server
+        /// TCPMUX or TCPMUXPLUS for services selected by name through the tcpmux INTERNAL
+        /// service, which have no port of their own; with TCPMUXPLUS, yinetd sends the "+" reply
+        pub r#type: ServiceType,
+
+        /// Forward connections to this TCP address instead of spawning a server: "<host> <port>"
+        /// The host is resolved when the config is loaded
+        pub redirect: Redirect,
+
+        /// TCP/UDP Port
+        /// Required for TCP/UDP services
+        pub port: u16,
+
         /// User ID to run the process
+        /// Same as a numeric `user`
         pub uid: u32,
 
+        /// User to run the process as, by name or ID
+        /// Supplementary groups are those of the user
+        pub user: Account,
+
+        /// Group to run the process as, by name or ID
+        /// Defaults to the primary group of `user`
+        pub group: Account,
+
         /// IP address to listen on
         /// Defaults to all if not specified
         pub listen_address: IpAddr,
+
+        /// Pass the bound socket to the server instead of accepted connections
+        /// Datagram services always wait
+        pub wait: YesNo,
+
+        /// Connection rate limit: "<conns_per_sec> <disable_secs> [burst]"
+        pub cps: Cps,
+
+        /// PROXY protocol header sent by a load balancer: v1, v2 or optional (either, or none)
+        /// The client address of the header is used for access control, logging and the
+        /// environment. With `optional`, clients without a header must send data first.
+        pub proxy_protocol: ProxyProtocol,
+
+        /// Only accept clients matching one of these addresses, networks or host names
+        pub only_from: AddrList,
+
+        /// Reject clients matching one of these addresses, networks or host names
+        /// When a client matches both lists, the more specific match wins
+        pub no_access: AddrList,
+
+        /// Local time intervals during which connections are accepted: "HH:MM-HH:MM ..."
+        pub access_times: AccessTimes,
+
+        /// Check clients against the tcpd `hosts.allow`/`hosts.deny` rules, by service name
+        pub tcp_wrappers: YesNo,
+
+        /// TCP wrappers allow rules
+        /// Defaults to /etc/hosts.allow
+        pub hosts_allow: PathBuf,
+
+        /// TCP wrappers deny rules
+        /// Defaults to /etc/hosts.deny
+        pub hosts_deny: PathBuf,
+
+        /// Maximum address space size of the server (RLIMIT_AS)
+        pub rlimit_as: RlimitSize,
+
+        /// Maximum CPU time of the server (RLIMIT_CPU)
+        pub rlimit_cpu: RlimitTime,
+
+        /// Maximum data segment size of the server (RLIMIT_DATA)
+        pub rlimit_data: RlimitSize,
+
+        /// Maximum resident set size of the server (RLIMIT_RSS)
+        pub rlimit_rss: RlimitSize,
+
+        /// Maximum stack size of the server (RLIMIT_STACK)
+        pub rlimit_stack: RlimitSize,
+
+        /// Maximum number of open files of the server (RLIMIT_NOFILE)
+        pub rlimit_files: Limit,
+
+        /// Maximum core file size of the server (RLIMIT_CORE)
+        pub rlimit_core: RlimitSize,
+
+        /// Scheduling priority of the server, from -20 (highest) to 19 (lowest)
+        pub nice: Nice,
+
+        /// I/O scheduling class of the server: realtime, best-effort or idle
+        /// Defaults to best-effort if only `ioprio` is set
+        pub ioprio_class: IoprioClass,
+
+        /// I/O priority of the server within its class, from 0 (highest) to 7 (lowest)
+        pub ioprio: Ioprio,
+
+        /// CPUs the server may run on, e.g. "0-3,6"
+        pub cpu_affinity: CpuList,
+
+        /// OOM killer score adjustment of the server, from -1000 to 1000
+        pub oom_score_adj: OomScoreAdj,
+
+        /// File mode creation mask of the server, in octal
+        /// Defaults to the umask of yinetd
+        pub umask: Umask,
+
+        /// Working directory of the server, inside the `chroot` if set
+        /// Defaults to the working directory of yinetd, or "/" with `chroot`
+        pub working_directory: PathBuf,
+
+        /// Directory to confine the server to; `server` is looked up inside it
+        pub chroot: PathBuf,
+
+        /// Environment variables of the server: "KEY=VALUE ..."
+        /// Servers start with a minimal environment (PATH only)
+        pub env: EnvVars,
+
+        /// Variables the server inherits from the environment of yinetd
+        pub passenv: EnvNames,
+
+        /// File of "KEY=VALUE" lines with more environment variables, read for each server
+        /// `env` takes precedence over the file, which takes precedence over `passenv`
+        pub env_file: PathBuf,
+
+        /// Describe the connection to the server with environment variables: PROTO,
+        /// TCPREMOTEIP, TCPREMOTEPORT, TCPLOCALIP, TCPLOCALPORT, YINETD_SERVICE and
+        /// YINETD_CONNECTION_ID
+        /// Defaults to yes
+        pub connection_env: YesNo,
+
+        /// Where the server's stderr goes: socket (to the client), file:<path> (appended),
+        /// null, or log (each line logged by yinetd at info level)
+        /// Defaults to the stderr of yinetd
+        pub stderr: StderrMode,
+
+        /// Unix domain socket path to listen on, or "@name" for a Linux abstract socket
+        /// Implies a Unix domain socket for stream/datagram services
+        pub listen_path: UnixAddr,
     }
 );